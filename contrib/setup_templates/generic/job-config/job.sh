@@ -25,7 +25,17 @@ then
     tar --extract --file "${FILE}" --directory runner
 fi
 
-export FORREST_API_URL="http://10.0.2.2:8080"
+export FORREST_API_URL="<FORREST_API_URL>"
 export FORREST_RUN_TOKEN_FILE="/home/runner/config/run-token"
 
+# Set if the machine may only access the internet via Forrest's egress proxy.
+EGRESS_PROXY="<EGRESS_PROXY>"
+
+if test -n "${EGRESS_PROXY}"
+then
+    export http_proxy="${EGRESS_PROXY}" https_proxy="${EGRESS_PROXY}"
+    export HTTP_PROXY="${EGRESS_PROXY}" HTTPS_PROXY="${EGRESS_PROXY}"
+    export no_proxy="10.0.2.101" NO_PROXY="10.0.2.101"
+fi
+
 ./runner/run.sh --jitconfig <JITCONFIG>
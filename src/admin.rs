@@ -0,0 +1,201 @@
+use hyper::body::Incoming;
+use hyper::{Method, Request, Response, StatusCode};
+use log::info;
+use subtle::ConstantTimeEq;
+
+use crate::config::Config;
+use crate::ingres::PollTrigger;
+use crate::machines::{image_rollback, Manager as MachineManager, Triplet};
+
+pub struct AdminHandler {
+    config: Config,
+    machine_manager: MachineManager,
+    poll_trigger: PollTrigger,
+}
+
+/// Get the bearer token from the Authorization header in a request
+fn token(request: &Request<Incoming>) -> &str {
+    request
+        .headers()
+        .get("Authorization")
+        .and_then(|auth| auth.to_str().ok())
+        .and_then(|auth| auth.strip_prefix("Bearer "))
+        .map(|token| token.trim())
+        .unwrap_or("")
+}
+
+/// Compare a token from a request to the admin token in constant time
+fn token_matches(admin_token: &str, token: &str) -> bool {
+    admin_token.as_bytes().ct_eq(token.as_bytes()).into()
+}
+
+fn text_response(status: StatusCode, text: String) -> Response<String> {
+    Response::builder().status(status).body(text).unwrap()
+}
+
+/// Get the generation from a `?generation=<N>` query (if any)
+fn generation(request: &Request<Incoming>) -> Result<Option<u32>, String> {
+    let generation = request
+        .uri()
+        .query()
+        .unwrap_or("")
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("generation="));
+
+    match generation.map(str::parse) {
+        None => Ok(None),
+        Some(Ok(generation)) => Ok(Some(generation)),
+        Some(Err(_)) => Err(format!("Invalid generation {}\n", generation.unwrap())),
+    }
+}
+
+impl AdminHandler {
+    pub fn new(config: Config, machine_manager: MachineManager, poll_trigger: PollTrigger) -> Self {
+        Self {
+            config,
+            machine_manager,
+            poll_trigger,
+        }
+    }
+
+    /// Handle a `POST /admin/...` request
+    ///
+    /// These let operators intervene without restarting the service,
+    /// e.g. to kill a stuck machine or to stop starting new machines before
+    /// maintenance.
+    /// Requests are authorized using the `host.admin_token`.
+    /// The admin API is disabled if no token is configured.
+    pub async fn handle(&self, request: Request<Incoming>) -> anyhow::Result<Response<String>> {
+        let cfg = self.config.get();
+
+        match cfg.host.admin_token.as_deref() {
+            Some(admin_token) if token_matches(admin_token, token(&request)) => {}
+            Some(_) => {
+                return Ok(text_response(
+                    StatusCode::UNAUTHORIZED,
+                    "Invalid admin token\n".into(),
+                ))
+            }
+            None => {
+                return Ok(text_response(
+                    StatusCode::NOT_FOUND,
+                    "The admin API is disabled\n".into(),
+                ))
+            }
+        }
+
+        if request.method() != Method::POST {
+            return Ok(text_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "Only POST is supported\n".into(),
+            ));
+        }
+
+        // input: "/admin/drain/<owner>/<repository>/<machine name>"
+        // split: ["", "admin", "drain", "<owner>", "<repository>", "<machine name>"]
+        let components: Vec<&str> = request.uri().path().split('/').collect();
+
+        let response = match components.as_slice() {
+            ["", "admin", "machines", runner_name, "kill"] => {
+                match self.machine_manager.kill(runner_name) {
+                    true => text_response(StatusCode::OK, format!("Killed {runner_name}\n")),
+                    false => text_response(
+                        StatusCode::NOT_FOUND,
+                        format!("There is no machine {runner_name}\n"),
+                    ),
+                }
+            }
+            ["", "admin", action @ ("drain" | "undrain")] => {
+                let drain = *action == "drain";
+
+                self.machine_manager.drain(None, drain);
+
+                match drain {
+                    true => info!("Draining the host on request"),
+                    false => info!("No longer draining the host on request"),
+                }
+
+                text_response(StatusCode::OK, format!("The host is {action}ed\n"))
+            }
+            ["", "admin", action @ ("drain" | "undrain"), owner, repository, machine_name] => {
+                let triplet = Triplet::new(owner, repository, machine_name);
+
+                let known = cfg
+                    .repositories
+                    .get(triplet.owner())
+                    .and_then(|repos| repos.get(triplet.repository()))
+                    .is_some_and(|repo| repo.machines.contains_key(triplet.machine_name()));
+
+                if !known {
+                    return Ok(text_response(
+                        StatusCode::NOT_FOUND,
+                        format!("Machine {triplet} is not configured\n"),
+                    ));
+                }
+
+                let drain = *action == "drain";
+
+                self.machine_manager.drain(Some(&triplet), drain);
+
+                match drain {
+                    true => info!("Draining {triplet} on request"),
+                    false => info!("No longer draining {triplet} on request"),
+                }
+
+                text_response(StatusCode::OK, format!("{triplet} is {action}ed\n"))
+            }
+            ["", "admin", "poll"] => {
+                self.poll_trigger.trigger();
+
+                text_response(StatusCode::ACCEPTED, "Polling GitHub now\n".into())
+            }
+            ["", "admin", "sweep"] => {
+                self.machine_manager.sweep_soon();
+
+                text_response(StatusCode::ACCEPTED, "Sweeping the machines now\n".into())
+            }
+            ["", "admin", "reload"] => match self.config.reload() {
+                Ok(()) => text_response(StatusCode::OK, "Re-read the config file\n".into()),
+                Err(e) => text_response(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    format!("Failed to re-read the config file: {e}\n"),
+                ),
+            },
+            ["", "admin", "images", owner, repository, machine_name, action] => {
+                let triplet = Triplet::new(owner, repository, machine_name);
+
+                let generation = match generation(&request) {
+                    Ok(generation) => generation,
+                    Err(msg) => return Ok(text_response(StatusCode::BAD_REQUEST, msg)),
+                };
+
+                let res = match *action {
+                    "rollback" => image_rollback(&cfg, &triplet, generation).map(|current| {
+                        info!("Rolled {triplet} back to generation {current} on request");
+                        format!("Rolled {triplet} back to generation {current}\n")
+                    }),
+                    "quarantine" => self
+                        .machine_manager
+                        .quarantine_image(&triplet, generation)
+                        .map(|(generation, fallback)| match fallback {
+                            Some(fallback) => format!(
+                                "Quarantined generation {generation} of {triplet}. New machines start from generation {fallback}\n"
+                            ),
+                            None => format!(
+                                "Quarantined generation {generation} of {triplet}. New machines start from the base image\n"
+                            ),
+                        }),
+                    _ => return Ok(text_response(StatusCode::NOT_FOUND, "File not found\n".into())),
+                };
+
+                match res {
+                    Ok(msg) => text_response(StatusCode::OK, msg),
+                    Err(e) => text_response(StatusCode::UNPROCESSABLE_ENTITY, format!("{e}\n")),
+                }
+            }
+            _ => text_response(StatusCode::NOT_FOUND, "File not found\n".into()),
+        };
+
+        Ok(response)
+    }
+}
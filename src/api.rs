@@ -2,21 +2,38 @@ use std::fs::Permissions;
 use std::os::unix::fs::PermissionsExt;
 use std::sync::Arc;
 
-use hyper::body::Incoming;
+use http_body_util::channel::Channel;
+use http_body_util::Either;
+use hyper::body::{Bytes, Incoming};
 use hyper::server::conn::http1::Builder as HttpConnectionBuilder;
 use hyper::service::service_fn;
-use hyper::{Request, Response, StatusCode};
+use hyper::{Method, Request, Response, StatusCode};
 use hyper_util::rt::TokioIo;
 use log::trace;
 use tokio::net::UnixListener;
+use tokio::task::JoinSet;
 
-use crate::artifacts::ArtifactsHandler;
+use crate::admin::AdminHandler;
+use crate::artifacts::{self, ArtifactsHandler};
 use crate::config::Config;
 use crate::ingres::WebhookHandler;
+use crate::logs::LogsHandler;
+use crate::machines::Machine;
+use crate::metrics::MetricsHandler;
+use crate::status::StatusHandler;
+
+/// The body of API responses
+///
+/// Most responses are a plain `String`, but some are streamed via a channel.
+pub type ApiBody = Either<String, Channel<Bytes>>;
 
 struct Handlers {
     webhook: WebhookHandler,
     artifacts: ArtifactsHandler,
+    logs: LogsHandler,
+    status: StatusHandler,
+    metrics: MetricsHandler,
+    admin: AdminHandler,
 }
 
 pub struct Api {
@@ -29,6 +46,10 @@ impl Api {
         config: Config,
         artifacts: ArtifactsHandler,
         webhook: WebhookHandler,
+        logs: LogsHandler,
+        status: StatusHandler,
+        metrics: MetricsHandler,
+        admin: AdminHandler,
     ) -> std::io::Result<Self> {
         let listener = {
             let cfg = config.get();
@@ -45,7 +66,14 @@ impl Api {
             listener
         };
 
-        let handlers = Arc::new(Handlers { artifacts, webhook });
+        let handlers = Arc::new(Handlers {
+            artifacts,
+            webhook,
+            logs,
+            status,
+            metrics,
+            admin,
+        });
 
         Ok(Self { listener, handlers })
     }
@@ -70,10 +98,38 @@ impl Api {
     }
 }
 
+/// Serve the API for a single machine on its own socket
+///
+/// The socket is forwarded into the guest, so that it can e.g. upload artifacts
+/// without any network setup on the host.
+/// Requests are implicitly authenticated as coming from `machine`.
+/// Connections are closed once the returned future is dropped.
+pub async fn serve_guest(listener: UnixListener, machine: Arc<Machine>) -> std::io::Result<()> {
+    let mut connections = JoinSet::new();
+
+    loop {
+        let (sock, _) = listener.accept().await?;
+        let machine = machine.clone();
+
+        let sock = TokioIo::new(sock);
+
+        connections.spawn(async move {
+            let service = service_fn(|req| guest_api_handler(req, &machine));
+
+            HttpConnectionBuilder::new()
+                .serve_connection(sock, service)
+                .await
+        });
+
+        // Clean up after connections that have already been closed.
+        while connections.try_join_next().is_some() {}
+    }
+}
+
 async fn api_handler(
     request: Request<Incoming>,
     handlers: &Handlers,
-) -> anyhow::Result<Response<String>> {
+) -> anyhow::Result<Response<ApiBody>> {
     let first_path_component = request
         .uri()
         .path()
@@ -84,12 +140,86 @@ async fn api_handler(
 
     trace!("API request for: {first_path_component}");
 
-    match first_path_component {
+    let response = match first_path_component {
         "artifact" => handlers.artifacts.handle(request).await,
         "webhook" => handlers.webhook.handle(request).await,
+        "machines" => return handlers.logs.handle(request).await,
+        "status" => handlers.status.handle(request).await,
+        "metrics" => handlers.metrics.handle(request).await,
+        "admin" => handlers.admin.handle(request).await,
+        _ => Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body("File not found".into())
+            .unwrap()),
+    };
+
+    response.map(|response| response.map(Either::Left))
+}
+
+async fn guest_api_handler(
+    request: Request<Incoming>,
+    machine: &Arc<Machine>,
+) -> anyhow::Result<Response<String>> {
+    let first_path_component = request
+        .uri()
+        .path()
+        .trim_start_matches('/')
+        .split('/')
+        .next()
+        .unwrap_or("");
+
+    trace!("Guest API request by {machine} for: {first_path_component}");
+
+    match first_path_component {
+        "artifact" => {
+            let extra_token = artifacts::guest_extra_token(&request, machine);
+            artifacts::upload(request, machine, &extra_token).await
+        }
+        "hold" => hold_handler(request, machine).await,
         _ => Ok(Response::builder()
             .status(StatusCode::NOT_FOUND)
             .body("File not found".into())
             .unwrap()),
     }
 }
+
+/// Request to keep a machine running after its job failed and grant the hold
+///
+/// `POST /hold` requests the hold while the job runs,
+/// `POST /hold/grant` grants it once the job has failed.
+async fn hold_handler(
+    request: Request<Incoming>,
+    machine: &Arc<Machine>,
+) -> anyhow::Result<Response<String>> {
+    if request.method() != Method::POST {
+        let response = Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .body("Only POST is supported\n".into());
+
+        return Ok(response.unwrap());
+    }
+
+    let response = match request.uri().path().trim_matches('/') {
+        "hold" => match machine.request_hold() {
+            Ok(duration) => Response::builder().status(StatusCode::OK).body(format!(
+                "The machine will be held for {duration:?} if its job fails\n"
+            )),
+            Err(reason) => Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(format!("{reason}\n")),
+        },
+        "hold/grant" => match machine.grant_hold().await {
+            Ok(duration) => Response::builder()
+                .status(StatusCode::OK)
+                .body(format!("The machine is held for {duration:?}\n")),
+            Err(reason) => Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(format!("The machine will not be held: {reason}\n")),
+        },
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body("File not found".into()),
+    };
+
+    Ok(response.unwrap())
+}
@@ -12,7 +12,7 @@ use rand::{rng, RngExt};
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
 
-use crate::machines::{Artifact, Manager as MachineManager};
+use crate::machines::{Artifact, Machine, Manager as MachineManager};
 
 pub struct ArtifactsHandler {
     machine_manager: MachineManager,
@@ -141,23 +141,7 @@ impl ArtifactsHandler {
     }
 
     pub async fn handle(&self, request: Request<Incoming>) -> anyhow::Result<Response<String>> {
-        if request.method() != Method::PUT {
-            return Ok(Response::builder()
-                .status(StatusCode::METHOD_NOT_ALLOWED)
-                .body("Only artifact upload is implemented".into())
-                .unwrap());
-        }
-
         let (run_token, extra_token) = tokens(&request);
-        let (name, req_path) = match path_components(&request) {
-            Some(np) => np,
-            None => {
-                return Ok(Response::builder()
-                    .status(StatusCode::BAD_REQUEST)
-                    .body("Request did not contain artifact store name or valid path".into())
-                    .unwrap());
-            }
-        };
 
         let machine = match self.machine_manager.machine_by_run_token(&run_token) {
             Some(machine) => machine,
@@ -169,76 +153,119 @@ impl ArtifactsHandler {
             }
         };
 
-        let artifact = match machine.artifact(&name, &extra_token) {
-            Some(artifact) => artifact,
-            None => {
-                return Ok(Response::builder()
-                    .status(StatusCode::NOT_FOUND)
-                    .body("The requested artifact is not configured for this machine type".into())
-                    .unwrap());
-            }
-        };
+        upload(request, &machine, &extra_token).await
+    }
+}
 
-        // From the PathBuf::push() documentation:
-        // "If path is absolute, it replaces the current path".
-        // So we have to make very sure that the path is always relative.
-        // The `path_components` function is written in a way that should make this impossible,
-        // but since the results would be catastrophic if it were to fail, check anyways and
-        // panic if it were the case.
-        assert!(req_path.is_relative());
-
-        let fs_path = {
-            let mut path = artifact.path();
-            path.push(&req_path);
-            path
-        };
+/// Get the extra token from a request that was made via the API socket of a machine
+///
+/// The machine is already identified by the socket the request came in on,
+/// so the run token is optional here:
+///
+///   - "Bearer <extra token>"
+///   - "Bearer <run token> <extra token>"
+pub fn guest_extra_token(request: &Request<Incoming>, machine: &Machine) -> String {
+    let (first, second) = tokens(request);
+
+    if first == machine.run_token() {
+        second
+    } else {
+        first
+    }
+}
 
-        // Construct a temporary path to upload to before atomically renaming the file in the end.
-        // fs_path = "/srv/forrest/artifacts/forrest-123456/lorem/ipsum.exe"
-        // fs_path_tmp = "/srv/forrest/artifacts/forrest-123456/lorem/ipsum.exe.tmp-frst-L0lja"
-        let fs_path_tmp = {
-            let mut suffix = b".tmp-frst-".to_vec();
-            suffix.extend(rng().sample_iter(&Alphanumeric).take(5));
-            let suffix = String::from_utf8(suffix).unwrap();
-
-            let mut path = fs_path.to_path_buf();
-            path.as_mut_os_string().push(suffix);
-            path
-        };
+/// Handle an artifact upload request by `machine`
+pub async fn upload(
+    request: Request<Incoming>,
+    machine: &Machine,
+    extra_token: &str,
+) -> anyhow::Result<Response<String>> {
+    if request.method() != Method::PUT {
+        return Ok(Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .body("Only artifact upload is implemented".into())
+            .unwrap());
+    }
+
+    let (name, req_path) = match path_components(&request) {
+        Some(np) => np,
+        None => {
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body("Request did not contain artifact store name or valid path".into())
+                .unwrap());
+        }
+    };
+
+    let artifact = match machine.artifact(&name, extra_token) {
+        Some(artifact) => artifact,
+        None => {
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body("The requested artifact is not configured for this machine type".into())
+                .unwrap());
+        }
+    };
+
+    // From the PathBuf::push() documentation:
+    // "If path is absolute, it replaces the current path".
+    // So we have to make very sure that the path is always relative.
+    // The `path_components` function is written in a way that should make this impossible,
+    // but since the results would be catastrophic if it were to fail, check anyways and
+    // panic if it were the case.
+    assert!(req_path.is_relative());
+
+    let fs_path = {
+        let mut path = artifact.path();
+        path.push(&req_path);
+        path
+    };
 
-        let body = request.into_body();
+    // Construct a temporary path to upload to before atomically renaming the file in the end.
+    // fs_path = "/srv/forrest/artifacts/forrest-123456/lorem/ipsum.exe"
+    // fs_path_tmp = "/srv/forrest/artifacts/forrest-123456/lorem/ipsum.exe.tmp-frst-L0lja"
+    let fs_path_tmp = {
+        let mut suffix = b".tmp-frst-".to_vec();
+        suffix.extend(rng().sample_iter(&Alphanumeric).take(5));
+        let suffix = String::from_utf8(suffix).unwrap();
+
+        let mut path = fs_path.to_path_buf();
+        path.as_mut_os_string().push(suffix);
+        path
+    };
 
-        match body_to_disk(body, &fs_path, &fs_path_tmp, &artifact).await {
-            Ok(()) => {
-                debug!("Saved artifact for {machine} as {}", fs_path.display());
+    let body = request.into_body();
 
-                let url = {
-                    let mut url = artifact.url().into_bytes();
-                    url.extend(req_path.as_os_str().as_bytes());
-                    url
-                };
+    match body_to_disk(body, &fs_path, &fs_path_tmp, &artifact).await {
+        Ok(()) => {
+            debug!("Saved artifact for {machine} as {}", fs_path.display());
 
-                Ok(Response::builder()
-                    .status(StatusCode::CREATED)
-                    .header("Content-Location", url)
-                    .body("".into())
-                    .unwrap())
-            }
-            Err(e) => {
-                warn!(
-                    "Failed to save artifact for {machine} as {}: {e}",
-                    fs_path.display()
-                );
-
-                // Best effort cleanup of the files we created
-                let _ = remove_file(fs_path_tmp);
-                let _ = remove_file(fs_path);
-
-                Ok(Response::builder()
-                    .status(StatusCode::INTERNAL_SERVER_ERROR)
-                    .body("Failed to store artifact to disk".into())
-                    .unwrap())
-            }
+            let url = {
+                let mut url = artifact.url().into_bytes();
+                url.extend(req_path.as_os_str().as_bytes());
+                url
+            };
+
+            Ok(Response::builder()
+                .status(StatusCode::CREATED)
+                .header("Content-Location", url)
+                .body("".into())
+                .unwrap())
+        }
+        Err(e) => {
+            warn!(
+                "Failed to save artifact for {machine} as {}: {e}",
+                fs_path.display()
+            );
+
+            // Best effort cleanup of the files we created
+            let _ = remove_file(fs_path_tmp);
+            let _ = remove_file(fs_path);
+
+            Ok(Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body("Failed to store artifact to disk".into())
+                .unwrap())
         }
     }
 }
@@ -4,8 +4,12 @@ use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::SystemTime;
 
+use anyhow::{anyhow, bail};
 use log::{error, info};
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::machines::Triplet;
 
 mod duration_human;
 mod github;
@@ -14,15 +18,23 @@ mod machine;
 mod size_in_bytes;
 
 pub use github::GitHubConfig;
-pub use host::HostConfig;
-pub use machine::{Artifact, MachineConfig, NetworkInterface, Repository, SeedBasePolicy};
-
-#[derive(Debug, Deserialize, PartialEq)]
+pub use host::{HostConfig, Retention};
+pub use machine::{
+    Addressing, Artifact, Egress, MachineConfig, MachineProfile, NetworkInterface,
+    PersistConflictPolicy, PersistOn, PortForward, Promotion, Repository, SeedBasePolicy,
+    ShareDriver, SocketMode,
+};
+
+#[derive(Debug, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct ConfigFile {
     pub github: GitHubConfig,
     pub host: HostConfig,
     pub repositories: HashMap<String, HashMap<String, Repository>>,
+
+    /// The SHA256 hash of the config file content
+    #[serde(skip)]
+    pub hash: String,
 }
 
 struct Inner {
@@ -56,13 +68,30 @@ fn remove_dot_keys(mapping: &mut yaml_serde::Mapping) {
         .for_each(remove_dot_keys);
 }
 
+// Configs are equal if they describe the same setup,
+// even if their files are formatted differently.
+impl PartialEq for ConfigFile {
+    fn eq(&self, other: &Self) -> bool {
+        self.github == other.github
+            && self.host == other.host
+            && self.repositories == other.repositories
+    }
+}
+
 impl ConfigFile {
-    fn from_reader<R>(reader: R) -> yaml_serde::Result<Arc<Self>>
+    fn from_reader<R>(mut reader: R) -> anyhow::Result<Arc<Self>>
     where
         R: std::io::Read,
     {
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content)?;
+
+        // Remember which config a machine was created with,
+        // e.g. to record it in the provenance of persisted images.
+        let hash = hex::encode(Sha256::digest(&content));
+
         // First we read the config file as generic yaml_serde Value.
-        let mut cfg: yaml_serde::Value = yaml_serde::from_reader(reader)?;
+        let mut cfg: yaml_serde::Value = yaml_serde::from_slice(&content)?;
 
         // Then we apply merges / overrides like these:
         //
@@ -97,9 +126,36 @@ impl ConfigFile {
         }
 
         // And then we convert to our config format.
-        let cfg = yaml_serde::from_value(cfg)?;
+        let cfg: Self = yaml_serde::from_value(cfg)?;
+
+        cfg.validate()?;
+
+        Ok(Arc::new(Self { hash, ..cfg }))
+    }
+
+    /// Check the parts of the config that can not be checked while deserializing it
+    fn validate(&self) -> anyhow::Result<()> {
+        for (owner, repos) in self.repositories.iter() {
+            for (repository, repo) in repos.iter() {
+                for (machine_name, machine_config) in repo.machines.iter() {
+                    let triplet = Triplet::new(owner, repository, machine_name);
+
+                    machine_config
+                        .validate()
+                        .map_err(|e| anyhow!("Invalid config for machine {triplet}: {e}"))?;
+
+                    // A TPM is enabled by creating a TPM state for the machine type.
+                    // The microvm profile has no ISA bus to attach it to.
+                    let has_tpm = triplet.machine_tmp_state_path(&self.host.base_dir).exists();
 
-        Ok(Arc::new(cfg))
+                    if machine_config.machine == MachineProfile::Microvm && has_tpm {
+                        bail!("Invalid config for machine {triplet}: The microvm machine profile does not support a TPM");
+                    }
+                }
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -140,6 +196,19 @@ impl Inner {
 
         self.config_file.clone()
     }
+
+    fn reload(&mut self) -> anyhow::Result<()> {
+        let mut fd = File::open(&self.path)?;
+
+        let last_modified = fd.metadata()?.modified()?;
+
+        self.config_file = ConfigFile::from_reader(&mut fd)?;
+        self.last_modified = last_modified;
+
+        info!("Re-read config file {}", self.path.display());
+
+        Ok(())
+    }
 }
 
 impl Config {
@@ -169,6 +238,14 @@ impl Config {
     pub fn get(&self) -> Arc<ConfigFile> {
         self.inner.lock().unwrap().get()
     }
+
+    /// Re-read the config file, even if it did not change on disk
+    ///
+    /// Unlike `get()` this returns reading or parsing errors to the caller.
+    /// The old version is kept in that case.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        self.inner.lock().unwrap().reload()
+    }
 }
 
 #[cfg(test)]
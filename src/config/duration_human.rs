@@ -24,3 +24,11 @@ where
 
     Ok(Duration::from_secs(value * multiplier))
 }
+
+/// Like `deserialize`, but for optional fields that should also be marked `#[serde(default)]`
+pub(super) fn deserialize_option<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize(deserializer).map(Some)
+}
@@ -1,12 +1,28 @@
 use std::path::PathBuf;
+use std::time::Duration;
 
 use serde::Deserialize;
 
+use super::duration_human;
 use super::size_in_bytes::SizeInBytes;
 
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Retention {
+    #[serde(default)]
+    #[serde(deserialize_with = "duration_human::deserialize_option")]
+    pub max_age: Option<Duration>,
+    pub max_count: Option<usize>,
+    pub max_size: Option<SizeInBytes>,
+}
+
 #[derive(Debug, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct HostConfig {
     pub base_dir: PathBuf,
     pub ram: SizeInBytes,
+    #[serde(default)]
+    pub retention: Retention,
+    pub notify_command: Option<PathBuf>,
+    pub admin_token: Option<String>,
 }
@@ -1,8 +1,11 @@
 use std::collections::HashMap;
 use std::path::PathBuf;
+use std::time::Duration;
 
+use anyhow::bail;
 use serde::Deserialize;
 
+use super::duration_human;
 use super::size_in_bytes::SizeInBytes;
 use crate::machines::Triplet;
 
@@ -23,6 +26,50 @@ pub enum SeedBasePolicy {
     Never,
 }
 
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PersistOn {
+    #[default]
+    Always,
+    Success,
+}
+
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+pub enum PersistConflictPolicy {
+    #[default]
+    #[serde(rename = "first_wins")]
+    First,
+    #[serde(rename = "last_wins")]
+    Last,
+    #[serde(rename = "newest_base_wins")]
+    NewestBase,
+}
+
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MachineProfile {
+    #[default]
+    Q35,
+    Microvm,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Firmware {
+    pub code: PathBuf,
+    pub vars: PathBuf,
+    #[serde(default)]
+    pub secure_boot: bool,
+}
+
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ShareDriver {
+    #[default]
+    Virtfs,
+    Virtiofs,
+}
+
 #[derive(Debug, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct ExposedDirectory {
@@ -30,6 +77,20 @@ pub struct ExposedDirectory {
     pub tag: String,
     #[serde(default)]
     pub writable: bool,
+    #[serde(default)]
+    pub driver: ShareDriver,
+}
+
+fn default_image_generations() -> u32 {
+    3
+}
+
+fn default_quarantine_after_failures() -> u32 {
+    1
+}
+
+fn default_quarantine_keep() -> u32 {
+    3
 }
 
 fn default_artifact_name() -> String {
@@ -47,18 +108,149 @@ pub struct Artifact {
     pub token: Option<String>,
 }
 
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Addressing {
+    pub mac_prefix: Option<String>,
+    pub mac_count: Option<u32>,
+    pub ip: Option<String>,
+}
+
 #[derive(Debug, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct NetworkInterfaceVde {
     pub path: PathBuf,
+    #[serde(default)]
+    pub addressing: Addressing,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkInterfaceTap {
+    pub bridge: String,
+    pub helper: Option<PathBuf>,
+    #[serde(default)]
+    pub addressing: Addressing,
 }
 
 #[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkInterfaceMacvtap {
+    pub interface: String,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SocketMode {
+    Connect,
+    Listen,
+    Mcast,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkInterfaceSocket {
+    pub mode: SocketMode,
+    pub address: String,
+    #[serde(default)]
+    pub addressing: Addressing,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkInterfaceStream {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub server: bool,
+    #[serde(default)]
+    pub addressing: Addressing,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
 #[serde(tag = "type")]
 #[serde(deny_unknown_fields)]
 pub enum NetworkInterface {
+    #[default]
+    #[serde(rename = "user")]
+    User,
     #[serde(rename = "vde")]
     Vde(NetworkInterfaceVde),
+    #[serde(rename = "tap")]
+    Tap(NetworkInterfaceTap),
+    #[serde(rename = "macvtap")]
+    Macvtap(NetworkInterfaceMacvtap),
+    #[serde(rename = "socket")]
+    Socket(NetworkInterfaceSocket),
+    #[serde(rename = "stream")]
+    Stream(NetworkInterfaceStream),
+    #[serde(rename = "none")]
+    None,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Egress {
+    pub allow: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PortForward {
+    pub guest: u16,
+    pub host: Option<u16>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct HoldOnFailure {
+    #[serde(deserialize_with = "duration_human::deserialize")]
+    pub duration: Duration,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PromotionCheck {
+    pub script: PathBuf,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(tag = "smoke_test")]
+#[serde(deny_unknown_fields)]
+pub enum Promotion {
+    #[default]
+    #[serde(rename = "none")]
+    None,
+    #[serde(rename = "register")]
+    Register,
+    #[serde(rename = "check")]
+    Check(PromotionCheck),
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Quarantine {
+    #[serde(default = "default_quarantine_after_failures")]
+    pub after_failures: u32,
+    #[serde(default = "default_quarantine_keep")]
+    pub keep: u32,
+}
+
+impl Default for Quarantine {
+    fn default() -> Self {
+        Self {
+            after_failures: default_quarantine_after_failures(),
+            keep: default_quarantine_keep(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct IdleSchedule {
+    pub from: u32,
+    pub until: u32,
+    pub min_idle: u32,
+    pub max_idle: Option<u32>,
 }
 
 #[derive(Debug, Deserialize, PartialEq)]
@@ -71,6 +263,31 @@ pub struct MachineConfig {
     #[serde(default)]
     pub use_base: SeedBasePolicy,
 
+    #[serde(default = "default_image_generations")]
+    pub image_generations: u32,
+
+    #[serde(default)]
+    pub persist_on: PersistOn,
+
+    #[serde(default)]
+    pub persist_conflict: PersistConflictPolicy,
+
+    #[serde(default)]
+    pub sparsify: bool,
+
+    #[serde(default)]
+    pub promotion: Promotion,
+
+    #[serde(default)]
+    pub quarantine: Quarantine,
+
+    #[serde(default)]
+    pub machine: MachineProfile,
+    pub kernel: Option<PathBuf>,
+    pub initrd: Option<PathBuf>,
+    pub append: Option<String>,
+    pub firmware: Option<Firmware>,
+
     pub cpus: u32,
     pub disk: SizeInBytes,
     pub ram: SizeInBytes,
@@ -81,13 +298,238 @@ pub struct MachineConfig {
     #[serde(default)]
     pub artifacts: Vec<Artifact>,
 
+    #[serde(default)]
+    pub uplink: NetworkInterface,
+
     #[serde(default)]
     pub network_interfaces: Vec<NetworkInterface>,
+
+    pub egress: Option<Egress>,
+
+    #[serde(default)]
+    pub port_forwards: Vec<PortForward>,
+
+    #[serde(default)]
+    pub ssh_access: bool,
+
+    pub hold_on_failure: Option<HoldOnFailure>,
+
+    #[serde(default)]
+    pub min_idle: u32,
+    pub max_idle: Option<u32>,
+
+    #[serde(default)]
+    pub idle_schedule: Vec<IdleSchedule>,
+}
+
+impl NetworkInterface {
+    /// How MAC and IP addresses are assigned to this interface
+    ///
+    /// Returns `None` for interfaces that do not need a unique MAC address
+    /// from the pool, like the private user mode network or macvtap interfaces,
+    /// which have a MAC address of their own.
+    pub fn addressing(&self) -> Option<&Addressing> {
+        match self {
+            Self::Vde(vde) => Some(&vde.addressing),
+            Self::Tap(tap) => Some(&tap.addressing),
+            Self::Socket(socket) => Some(&socket.addressing),
+            Self::Stream(stream) => Some(&stream.addressing),
+            Self::User | Self::Macvtap(_) | Self::None => None,
+        }
+    }
+}
+
+impl IdleSchedule {
+    fn validate(&self) -> anyhow::Result<()> {
+        // `until: 24` is the end of the day, so that an entry can cover it all.
+        if self.from > 23 || self.until > 24 {
+            bail!(
+                "idle_schedule hours must be between 0 and 23 for from and 0 and 24 for until (got from: {}, until: {})",
+                self.from,
+                self.until
+            );
+        }
+
+        if self.from == self.until {
+            bail!(
+                "idle_schedule entry with from: {} and until: {} does not cover any hour. Use from: 0 and until: 24 to cover the whole day",
+                self.from,
+                self.until
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Does this schedule entry cover the given hour of the day?
+    ///
+    /// Entries may wrap around midnight, e.g. `from: 22` and `until: 6`.
+    fn covers(&self, hour: u32) -> bool {
+        if self.from <= self.until {
+            (self.from..self.until).contains(&hour)
+        } else {
+            hour >= self.from || hour < self.until
+        }
+    }
+}
+
+impl MachineConfig {
+    /// Check the settings that can not be checked while deserializing them
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for entry in &self.idle_schedule {
+            entry.validate()?;
+        }
+
+        // Static IPs are offset by the index of the MAC address in its range,
+        // which is shared by all interfaces with the same prefix.
+        // Interfaces using the default prefix would take each other's IPs.
+        let interfaces = std::iter::once(&self.uplink).chain(&self.network_interfaces);
+
+        for addressing in interfaces.filter_map(NetworkInterface::addressing) {
+            if addressing.ip.is_some() && addressing.mac_prefix.is_none() {
+                bail!("Network interfaces with a static `ip` also need a `mac_prefix`");
+            }
+        }
+
+        // Egress filtering relies on the qemu user mode network stack.
+        // Other uplinks and additional network interfaces would allow the
+        // guest to bypass it.
+        if self.egress.is_some() {
+            if !matches!(self.uplink, NetworkInterface::User | NetworkInterface::None) {
+                bail!("Egress filtering is only supported with a `user` or `none` uplink");
+            }
+
+            if !self.network_interfaces.is_empty() {
+                bail!("Egress filtering can not be combined with `network_interfaces`");
+            }
+        }
+
+        if self.machine == MachineProfile::Microvm {
+            if self.kernel.is_none() {
+                bail!("The microvm machine profile requires a kernel to boot");
+            }
+
+            if self.firmware.is_some() {
+                bail!("The microvm machine profile does not support UEFI firmware");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The minimum and maximum number of idle machines to keep at the given hour of the day
+    ///
+    /// The first matching entry in the `idle_schedule` wins.
+    /// If no entry matches, the `min_idle` and `max_idle` values are used.
+    /// The maximum is never below the minimum.
+    pub fn idle_limits(&self, hour: u32) -> (u32, u32) {
+        let (min_idle, max_idle) = self
+            .idle_schedule
+            .iter()
+            .find(|entry| entry.covers(hour))
+            .map(|entry| (entry.min_idle, entry.max_idle))
+            .unwrap_or((self.min_idle, self.max_idle));
+
+        let max_idle = max_idle.unwrap_or(min_idle).max(min_idle);
+
+        (min_idle, max_idle)
+    }
 }
 
 #[derive(Debug, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct Repository {
     pub persistence_token: Option<String>,
+    pub log_token: Option<String>,
+    pub max_held_ram: Option<SizeInBytes>,
     pub machines: HashMap<String, MachineConfig>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Addressing, IdleSchedule, NetworkInterface, NetworkInterfaceVde};
+
+    #[test]
+    fn idle_schedule_wraps_around_midnight() {
+        let night = IdleSchedule {
+            from: 22,
+            until: 6,
+            min_idle: 0,
+            max_idle: None,
+        };
+
+        assert!(night.covers(22));
+        assert!(night.covers(0));
+        assert!(night.covers(5));
+        assert!(!night.covers(6));
+        assert!(!night.covers(12));
+
+        let day = IdleSchedule {
+            from: 6,
+            until: 22,
+            min_idle: 2,
+            max_idle: Some(4),
+        };
+
+        assert!(day.covers(6));
+        assert!(day.covers(21));
+        assert!(!day.covers(22));
+        assert!(!day.covers(3));
+    }
+
+    #[test]
+    fn idle_schedule_validation() {
+        let entry = |from, until| IdleSchedule {
+            from,
+            until,
+            min_idle: 0,
+            max_idle: None,
+        };
+
+        assert!(entry(22, 6).validate().is_ok());
+        assert!(entry(0, 23).validate().is_ok());
+        assert!(entry(6, 24).validate().is_ok());
+        assert!(entry(0, 24).validate().is_ok());
+        assert!(entry(6, 25).validate().is_err());
+        assert!(entry(24, 6).validate().is_err());
+
+        // Until the end of the day and the whole day
+        assert!(entry(22, 24).covers(23));
+        assert!(!entry(22, 24).covers(0));
+        assert!(entry(22, 0).covers(23));
+        assert!((0..24).all(|hour| entry(0, 24).covers(hour)));
+        assert!(entry(8, 8).validate().is_err());
+    }
+
+    #[test]
+    fn static_ip_requires_mac_prefix() {
+        let interface = |mac_prefix: Option<&str>| {
+            NetworkInterface::Vde(NetworkInterfaceVde {
+                path: "/run/vde/lab.ctl".into(),
+                addressing: Addressing {
+                    mac_prefix: mac_prefix.map(Into::into),
+                    mac_count: None,
+                    ip: Some("192.168.10.100/24".into()),
+                },
+            })
+        };
+
+        let validate = |interface| {
+            let yaml = "
+                base_image: /srv/base.qcow2
+                setup_template:
+                  path: /etc/forrest/templates/generic
+                cpus: 2
+                ram: 4G
+                disk: 8G
+            ";
+
+            let mut config: super::MachineConfig = yaml_serde::from_str(yaml).unwrap();
+            config.network_interfaces.push(interface);
+            config.validate()
+        };
+
+        assert!(validate(interface(Some("52:54:00:12"))).is_ok());
+        assert!(validate(interface(None)).is_err());
+    }
+}
@@ -0,0 +1,356 @@
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail};
+use http_body_util::{BodyExt, Empty};
+use hyper::body::{Bytes, Incoming};
+use hyper::client::conn::http1::handshake;
+use hyper::{Method, Request, Response};
+use hyper_util::rt::TokioIo;
+use log::debug;
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tokio::net::UnixStream;
+
+use crate::config::Config;
+use crate::machines::{image_info, print_image_info, Triplet};
+use crate::status::Status;
+
+const GIB: f64 = (1024 * 1024 * 1024) as f64;
+
+/// A client for the API socket of a running Forrest service
+struct Client {
+    socket: PathBuf,
+    admin_token: Option<String>,
+}
+
+impl Client {
+    fn new(config_path: &str) -> anyhow::Result<Self> {
+        let cfg = Config::new(config_path)?.get();
+
+        Ok(Self {
+            socket: cfg.host.base_dir.join("api.sock"),
+            admin_token: cfg.host.admin_token.clone(),
+        })
+    }
+
+    /// Send a request to the API, authenticated using the admin token if `admin` is set
+    async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        admin: bool,
+    ) -> anyhow::Result<Response<Incoming>> {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(path)
+            .header("Host", "localhost");
+
+        if admin {
+            let admin_token = match &self.admin_token {
+                Some(admin_token) => admin_token,
+                None => bail!("This requires a host.admin_token in the config"),
+            };
+
+            request = request.header("Authorization", format!("Bearer {admin_token}"));
+        }
+
+        let request = request.body(Empty::<Bytes>::new())?;
+
+        let sock = UnixStream::connect(&self.socket)
+            .await
+            .map_err(|e| anyhow!("Failed to connect to {}: {e}", self.socket.display()))?;
+
+        let (mut sender, connection) = handshake(TokioIo::new(sock)).await?;
+
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                debug!("API connection failed: {e}");
+            }
+        });
+
+        Ok(sender.send_request(request).await?)
+    }
+
+    /// Send a request and get the response body, failing if the request was not successful
+    async fn text(&self, method: Method, path: &str, admin: bool) -> anyhow::Result<String> {
+        let response = self.request(method, path, admin).await?;
+        let status = response.status();
+
+        let body = response.into_body().collect().await?.to_bytes();
+        let body = String::from_utf8_lossy(&body).into_owned();
+
+        if !status.is_success() {
+            bail!("{status}: {}", body.trim_end());
+        }
+
+        Ok(body)
+    }
+
+    async fn status(&self) -> anyhow::Result<Status> {
+        let body = self.text(Method::GET, "/status", false).await?;
+
+        Ok(serde_json::from_str(&body)?)
+    }
+}
+
+/// Print rows of cells as table with aligned columns
+fn print_table(header: &[&str], rows: &[Vec<String>]) {
+    let mut widths: Vec<usize> = header.iter().map(|cell| cell.len()).collect();
+
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let print_row = |cells: &[&str]| {
+        let line: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect();
+
+        println!("{}", line.join("  ").trim_end());
+    };
+
+    print_row(header);
+
+    for row in rows {
+        let cells: Vec<&str> = row.iter().map(String::as_str).collect();
+        print_row(&cells);
+    }
+}
+
+fn print_json(value: &impl Serialize) -> anyhow::Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+
+    Ok(())
+}
+
+/// Format a duration like `42s`, `5m12s` or `3h05m`
+fn format_secs(secs: u64) -> String {
+    match secs {
+        0..60 => format!("{secs}s"),
+        60..3600 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m", secs / 3600, (secs % 3600) / 60),
+    }
+}
+
+fn format_gib(bytes: u64) -> String {
+    format!("{:.1} GiB", bytes as f64 / GIB)
+}
+
+/// Print the RAM usage, drain state and the demand for and supply of machines
+pub async fn status(config_path: &str, json: bool) -> anyhow::Result<()> {
+    let status = Client::new(config_path)?.status().await?;
+
+    if json {
+        return print_json(&status);
+    }
+
+    let machines = &status.machines;
+
+    println!(
+        "RAM: {} of {} used, {} available",
+        format_gib(machines.ram_used),
+        format_gib(machines.ram_total),
+        format_gib(machines.ram_available),
+    );
+
+    if machines.host_drained {
+        println!("The host is drained");
+    }
+
+    if !machines.drained_machines.is_empty() {
+        println!("Drained: {}", machines.drained_machines.join(", "));
+    }
+
+    println!();
+
+    let rows: Vec<Vec<String>> = machines
+        .supply
+        .iter()
+        .map(|supply| {
+            vec![
+                supply.triplet.clone(),
+                supply.demand.to_string(),
+                supply.available.to_string(),
+                supply.machines.to_string(),
+            ]
+        })
+        .collect();
+
+    print_table(&["MACHINE TYPE", "QUEUED", "AVAILABLE", "MACHINES"], &rows);
+
+    Ok(())
+}
+
+/// Print the machines of the running service
+pub async fn machines(config_path: &str, json: bool) -> anyhow::Result<()> {
+    let status = Client::new(config_path)?.status().await?;
+
+    if json {
+        return print_json(&status.machines.machines);
+    }
+
+    let rows: Vec<Vec<String>> = status
+        .machines
+        .machines
+        .iter()
+        .map(|machine| {
+            vec![
+                machine.runner_name.clone(),
+                machine.triplet.clone(),
+                machine.status.clone(),
+                format_secs(machine.status_secs),
+                format_gib(machine.ram_required),
+            ]
+        })
+        .collect();
+
+    print_table(
+        &["RUNNER NAME", "MACHINE TYPE", "STATUS", "SINCE", "RAM"],
+        &rows,
+    );
+
+    Ok(())
+}
+
+/// Print the jobs tracked by the running service
+pub async fn jobs(config_path: &str, json: bool) -> anyhow::Result<()> {
+    let status = Client::new(config_path)?.status().await?;
+
+    if json {
+        return print_json(&status.jobs);
+    }
+
+    let mut rows = Vec::new();
+
+    for job in &status.jobs {
+        // Use the same names for the status as the GitHub API and the JSON output.
+        let job_status = serde_json::to_value(&job.status)?;
+
+        rows.push(vec![
+            job.job_id.to_string(),
+            job.run_id.to_string(),
+            job.triplet.clone(),
+            job_status.as_str().unwrap_or_default().to_owned(),
+            format_secs(job.age_secs),
+        ]);
+    }
+
+    print_table(
+        &["JOB ID", "RUN ID", "MACHINE TYPE", "STATUS", "AGE"],
+        &rows,
+    );
+
+    Ok(())
+}
+
+/// Print the image generations of all configured machine types
+///
+/// These are read from disk instead of the API, like for `forrest image list`.
+pub fn images(config_path: &str, json: bool) -> anyhow::Result<()> {
+    let cfg = Config::new(config_path)?.get();
+
+    let mut triplets: Vec<Triplet> = cfg
+        .repositories
+        .iter()
+        .flat_map(|(owner, repos)| {
+            repos.iter().flat_map(move |(repository, repo)| {
+                repo.machines
+                    .keys()
+                    .map(move |machine_name| Triplet::new(owner, repository, machine_name))
+            })
+        })
+        .collect();
+
+    triplets.sort_by_key(Triplet::to_string);
+
+    let infos = triplets
+        .iter()
+        .map(|triplet| image_info(&cfg, triplet))
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    if json {
+        return print_json(&infos);
+    }
+
+    for (i, info) in infos.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+
+        println!("{}:", info.triplet);
+        print_image_info(info);
+    }
+
+    Ok(())
+}
+
+/// Kill a machine, even if it is running a job
+pub async fn kill(config_path: &str, runner_name: &str) -> anyhow::Result<()> {
+    let path = format!("/admin/machines/{runner_name}/kill");
+    let response = Client::new(config_path)?
+        .text(Method::POST, &path, true)
+        .await?;
+
+    print!("{response}");
+
+    Ok(())
+}
+
+/// Stop (or resume) starting new machines on the host or for a machine type
+pub async fn drain(config_path: &str, triplet: Option<&str>, drain: bool) -> anyhow::Result<()> {
+    let action = match drain {
+        true => "drain",
+        false => "undrain",
+    };
+
+    let path = match triplet {
+        Some(triplet) => {
+            let triplet: Triplet = triplet.parse().map_err(anyhow::Error::msg)?;
+            format!("/admin/{action}/{triplet}")
+        }
+        None => format!("/admin/{action}"),
+    };
+
+    let response = Client::new(config_path)?
+        .text(Method::POST, &path, true)
+        .await?;
+
+    print!("{response}");
+
+    Ok(())
+}
+
+/// Print the serial console log of a machine and (with `follow`) anything it logs until it stops
+pub async fn logs(config_path: &str, runner_name: &str, follow: bool) -> anyhow::Result<()> {
+    let path = match follow {
+        true => format!("/machines/{runner_name}/log"),
+        false => format!("/machines/{runner_name}/log?follow=false"),
+    };
+
+    let response = Client::new(config_path)?
+        .request(Method::GET, &path, true)
+        .await?;
+
+    let status = response.status();
+    let mut body = response.into_body();
+
+    if !status.is_success() {
+        let body = body.collect().await?.to_bytes();
+        bail!("{status}: {}", String::from_utf8_lossy(&body).trim_end());
+    }
+
+    let mut stdout = tokio::io::stdout();
+
+    while let Some(frame) = body.frame().await {
+        if let Some(data) = frame?.data_ref() {
+            stdout.write_all(data).await?;
+            stdout.flush().await?;
+        }
+    }
+
+    Ok(())
+}
@@ -1,5 +1,5 @@
 mod poll;
 mod webhook;
 
-pub use poll::Poller;
+pub use poll::{PollTrigger, Poller};
 pub use webhook::WebhookHandler;
@@ -4,11 +4,13 @@ use std::sync::{Arc, Mutex};
 use chrono::{TimeDelta, Utc};
 use log::{debug, error, info};
 use octocrab::models::RunId;
+use tokio::sync::Notify;
 
 use crate::auth::Auth;
 use crate::config::{Config, Repository};
 use crate::jobs::Manager as JobManager;
 use crate::machines::OwnerAndRepo;
+use crate::metrics::Metrics;
 
 /// The cut-off point when fetching the initial run list.
 /// Once a run is encountered that is older than this the search will stop.
@@ -18,18 +20,42 @@ pub struct Poller {
     auth: Arc<Auth>,
     config: Config,
     job_manager: JobManager,
+    metrics: Metrics,
     most_recent_run_id: Arc<Mutex<HashMap<OwnerAndRepo, RunId>>>,
+    poll_now: Arc<Notify>,
+}
+
+/// Can be used to make the `Poller` poll right away instead of at its next interval
+#[derive(Clone)]
+pub struct PollTrigger {
+    poll_now: Arc<Notify>,
+}
+
+impl PollTrigger {
+    pub fn trigger(&self) {
+        self.poll_now.notify_one();
+    }
 }
 
 impl Poller {
-    pub fn new(config: Config, auth: Arc<Auth>, job_manager: JobManager) -> Self {
+    pub fn new(config: Config, auth: Arc<Auth>, job_manager: JobManager, metrics: Metrics) -> Self {
         let most_recent_run_id = Arc::new(Mutex::new(HashMap::new()));
+        let poll_now = Arc::new(Notify::new());
 
         Self {
             auth,
             config,
             job_manager,
+            metrics,
             most_recent_run_id,
+            poll_now,
+        }
+    }
+
+    /// Get an object that can be used to trigger a poll from elsewhere
+    pub fn trigger(&self) -> PollTrigger {
+        PollTrigger {
+            poll_now: self.poll_now.clone(),
         }
     }
 
@@ -44,7 +70,9 @@ impl Poller {
         let mut prev_run_id = None;
 
         for page in 1u32.. {
-            let workflow_runs = workflows.list_all_runs().page(page).send().await?;
+            let workflow_runs = workflows.list_all_runs().page(page).send().await;
+            self.metrics.github_api_call(&workflow_runs);
+            let workflow_runs = workflow_runs?;
 
             if page == 0 {
                 // The first run on the first page is the newest one.
@@ -90,7 +118,9 @@ impl Poller {
         let workflows = octocrab.workflows(oar.owner(), oar.repository());
 
         for page in 1u32.. {
-            let jobs = workflows.list_jobs(run_id).page(page).send().await?;
+            let jobs = workflows.list_jobs(run_id).page(page).send().await;
+            self.metrics.github_api_call(&jobs);
+            let jobs = jobs?;
 
             if jobs.items.is_empty() {
                 // We have reached an empty page. Time to stop.
@@ -112,6 +142,7 @@ impl Poller {
                     job.id,
                     run_id,
                     job.status,
+                    job.conclusion,
                     job.runner_name.as_deref(),
                 );
             }
@@ -155,6 +186,24 @@ impl Poller {
         }
     }
 
+    /// Record how many API calls we have left as `user`
+    ///
+    /// Requests for the rate limit do not count against it.
+    async fn update_rate_limit(&self, user: &str) {
+        let octocrab = self.auth.user(user).unwrap();
+
+        let rate_limit = octocrab.ratelimit().get().await;
+        self.metrics.github_api_call(&rate_limit);
+
+        match rate_limit {
+            Ok(rate_limit) => {
+                let remaining = rate_limit.resources.core.remaining as u64;
+                self.metrics.github_rate_limit_remaining(user, remaining);
+            }
+            Err(e) => error!("Failed to get the API rate limit for {user}: {e}"),
+        }
+    }
+
     /// Poll the list of runs and jobs for each registered repository
     ///
     /// How far back to go in the run history is decided by `MAX_NEW_RUN_AGE`,
@@ -177,7 +226,10 @@ impl Poller {
                 .installations()
                 .page(page)
                 .send()
-                .await?;
+                .await;
+
+            self.metrics.github_api_call(&installations);
+            let installations = installations?;
 
             if installations.items.is_empty() {
                 // We have reached an empty page. Time to stop.
@@ -199,6 +251,8 @@ impl Poller {
                     // The list of repositories always comes from the config file
                     // and not the API.
                     self.poll_user(user, repos, &mut runs_of_interest).await;
+
+                    self.update_rate_limit(user).await;
                 } else {
                     // If the runner application is listed as public then basically
                     // anyone can install it.
@@ -214,6 +268,7 @@ impl Poller {
     /// Periodically poll the runs and jobs for each registered repository.
     ///
     /// The polling period is determined by the config file.
+    /// A poll can be requested early via a `PollTrigger`.
     pub async fn poll(&self) -> std::io::Result<()> {
         loop {
             debug!("Poll for pending jobs");
@@ -222,7 +277,10 @@ impl Poller {
                 error!("Failed to poll for installations: {e}");
             }
 
-            tokio::time::sleep(self.config.get().github.polling_interval).await;
+            tokio::select! {
+                _ = tokio::time::sleep(self.config.get().github.polling_interval) => {}
+                _ = self.poll_now.notified() => debug!("Polling on request"),
+            }
         }
     }
 }
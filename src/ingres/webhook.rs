@@ -14,19 +14,26 @@ use crate::auth::Auth;
 use crate::config::Config;
 use crate::jobs::Manager as JobManager;
 use crate::machines::OwnerAndRepo;
+use crate::metrics::Metrics;
+
+// Deliveries with missing or invalid signatures are counted under this event
+// type, so that unauthenticated requests can not create arbitrary time series.
+const UNVERIFIED_EVENT: &str = "unverified";
 
 pub struct WebhookHandler {
     config: Config,
     auth: Arc<Auth>,
     job_manager: JobManager,
+    metrics: Metrics,
 }
 
 impl WebhookHandler {
-    pub fn new(config: Config, auth: Arc<Auth>, job_manager: JobManager) -> Self {
+    pub fn new(config: Config, auth: Arc<Auth>, job_manager: JobManager, metrics: Metrics) -> Self {
         Self {
             config,
             auth,
             job_manager,
+            metrics,
         }
     }
 
@@ -71,6 +78,8 @@ impl WebhookHandler {
         let signature = match parts.headers.get("X-Hub-Signature-256") {
             Some(sig) => sig,
             None => {
+                self.metrics.webhook_delivery(UNVERIFIED_EVENT, "missing");
+
                 return Ok(Response::builder()
                     .status(StatusCode::BAD_REQUEST)
                     .body("Request is missing an X-Hub-Signature-256 Header".into())
@@ -87,6 +96,8 @@ impl WebhookHandler {
         let signature = match signature {
             Some(sig) => sig,
             None => {
+                self.metrics.webhook_delivery(UNVERIFIED_EVENT, "missing");
+
                 return Ok(Response::builder()
                     .status(StatusCode::BAD_REQUEST)
                     .body("Failed to decode X-Hub-Signature-256 Header".into())
@@ -108,12 +119,16 @@ impl WebhookHandler {
             let content_valid = hmac.verify_slice(&signature);
 
             if content_valid.is_err() {
+                self.metrics.webhook_delivery(UNVERIFIED_EVENT, "invalid");
+
                 return Ok(Response::builder()
                     .status(StatusCode::BAD_REQUEST)
                     .body("Signature validation failed".into())
                     .unwrap());
             }
 
+            self.metrics.webhook_delivery(event_type, "valid");
+
             content
         };
 
@@ -217,6 +232,7 @@ impl WebhookHandler {
                 workflow_job.id,
                 workflow_job.run_id,
                 workflow_job.status,
+                workflow_job.conclusion,
                 workflow_job.runner_name.as_deref(),
             );
         }
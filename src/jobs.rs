@@ -1,4 +1,4 @@
 mod job;
 mod manager;
 
-pub use manager::Manager;
+pub use manager::{JobInfo, Manager};
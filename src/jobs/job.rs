@@ -1,3 +1,5 @@
+use std::time::Instant;
+
 use octocrab::models::workflows::Status;
 use octocrab::models::{JobId, RunId};
 
@@ -8,6 +10,7 @@ pub(super) struct Job {
     job_id: JobId,
     run_id: RunId,
     status: Status,
+    created: Instant,
 }
 
 impl Job {
@@ -17,6 +20,7 @@ impl Job {
             job_id,
             run_id,
             status,
+            created: Instant::now(),
         }
     }
 
@@ -32,6 +36,15 @@ impl Job {
         self.run_id
     }
 
+    pub(super) fn status(&self) -> &Status {
+        &self.status
+    }
+
+    /// When we first learned about this job
+    pub(super) fn created(&self) -> Instant {
+        self.created
+    }
+
     pub(super) fn is_queued(&self) -> bool {
         matches!(self.status, Status::Queued)
     }
@@ -2,12 +2,14 @@ use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use octocrab::models::workflows::Status;
+use octocrab::models::workflows::{Conclusion, Status};
 use octocrab::models::{JobId, RunId};
+use serde::{Deserialize, Serialize};
 use tokio::task::JoinHandle;
 
 use super::job::Job;
 use crate::machines::{Manager as MachineManager, OwnerAndRepo, Triplet};
+use crate::metrics::Metrics;
 
 // The `status_feedback()` method is called for each webhook event
 // and each job that comes up in a poll.
@@ -17,15 +19,27 @@ use crate::machines::{Manager as MachineManager, OwnerAndRepo, Triplet};
 // the machine manager.
 const UPDATE_SOON_DELAY: Duration = Duration::from_secs(5);
 
+/// A snapshot of the state of a job, e.g. for the status API
+#[derive(Serialize, Deserialize)]
+pub struct JobInfo {
+    pub triplet: String,
+    pub job_id: JobId,
+    pub run_id: RunId,
+    pub status: Status,
+    /// How long ago we learned about the job
+    pub age_secs: u64,
+}
+
 #[derive(Clone)]
 pub struct Manager {
     machine_manager: MachineManager,
+    metrics: Metrics,
     jobs: Arc<Mutex<Vec<Job>>>,
     update_soon_task: Arc<Mutex<JoinHandle<()>>>,
 }
 
 impl Manager {
-    pub fn new(machine_manager: MachineManager) -> Self {
+    pub fn new(machine_manager: MachineManager, metrics: Metrics) -> Self {
         let jobs = Arc::new(Mutex::new(Vec::new()));
 
         // A placeholder task that finishes immediately.
@@ -34,6 +48,7 @@ impl Manager {
 
         Self {
             machine_manager,
+            metrics,
             jobs,
             update_soon_task,
         }
@@ -58,6 +73,22 @@ impl Manager {
         res
     }
 
+    /// Get a snapshot of all jobs we are tracking
+    pub fn status(&self) -> Vec<JobInfo> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|job| JobInfo {
+                triplet: job.triplet().to_string(),
+                job_id: job.job_id(),
+                run_id: job.run_id(),
+                status: job.status().clone(),
+                age_secs: job.created().elapsed().as_secs(),
+            })
+            .collect()
+    }
+
     /// Update the status of a job
     ///
     /// This is called by the poller and webhook ingres tasks.
@@ -67,6 +98,7 @@ impl Manager {
         job_id: JobId,
         run_id: RunId,
         status: Status,
+        conclusion: Option<Conclusion>,
         runner_name: Option<&str>,
     ) {
         if let (Status::InProgress, Some(runner_name)) = (&status, runner_name) {
@@ -75,6 +107,9 @@ impl Manager {
             // Make sure the runner does not become eligible for termination.
             self.machine_manager
                 .status_feedback(triplet, runner_name, Some(true), true);
+
+            self.machine_manager
+                .job_started(triplet, runner_name, run_id, job_id);
         }
 
         if let (Status::Completed | Status::Failed, Some(runner_name)) = (&status, runner_name) {
@@ -82,6 +117,17 @@ impl Manager {
             // We do however not know if it is still online.
             self.machine_manager
                 .status_feedback(triplet, runner_name, None, false);
+
+            // The conclusion decides if the machine image may be persisted.
+            if let Some(conclusion) = conclusion {
+                self.machine_manager.job_completed(
+                    triplet,
+                    runner_name,
+                    run_id,
+                    job_id,
+                    conclusion,
+                );
+            }
         }
 
         let mut jobs = self.jobs.lock().unwrap();
@@ -98,7 +144,15 @@ impl Manager {
                 true
             }
             (Status::Pending | Status::Queued | Status::InProgress, Some(index)) => {
-                jobs[index].update_status(status)
+                let job = &mut jobs[index];
+
+                // The job waited in the queue from when we learned about it
+                // until a machine picked it up.
+                if status == Status::InProgress && job.status() != &Status::InProgress {
+                    self.metrics.queue_wait(triplet, job.created().elapsed());
+                }
+
+                job.update_status(status)
             }
 
             // The job does not need further tracking from our side.
@@ -0,0 +1,192 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use http_body_util::channel::{Channel, Sender};
+use http_body_util::Either;
+use hyper::body::{Bytes, Incoming};
+use hyper::{Method, Request, Response, StatusCode};
+use log::{debug, trace};
+use subtle::ConstantTimeEq;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+
+use crate::api::ApiBody;
+use crate::config::Config;
+use crate::machines::{find_run_dir, Manager as MachineManager};
+
+// How often to check the log file for new content while the machine is running.
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(500);
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+pub struct LogsHandler {
+    config: Config,
+    machine_manager: MachineManager,
+}
+
+/// Get the bearer token from the Authorization header in a request
+fn token(request: &Request<Incoming>) -> &str {
+    request
+        .headers()
+        .get("Authorization")
+        .and_then(|auth| auth.to_str().ok())
+        .and_then(|auth| auth.strip_prefix("Bearer "))
+        .map(|token| token.trim())
+        .unwrap_or("")
+}
+
+/// Compare a token from a request to a valid token in constant time
+///
+/// A plain comparison returns at the first mismatching byte, which would allow
+/// guessing a token byte by byte by timing the responses.
+fn token_matches(valid_token: &str, token: &str) -> bool {
+    valid_token.as_bytes().ct_eq(token.as_bytes()).into()
+}
+
+fn text_response(status: StatusCode, text: &str) -> Response<ApiBody> {
+    Response::builder()
+        .status(status)
+        .body(Either::Left(text.into()))
+        .unwrap()
+}
+
+impl LogsHandler {
+    pub fn new(config: Config, machine_manager: MachineManager) -> Self {
+        Self {
+            config,
+            machine_manager,
+        }
+    }
+
+    /// Handle a `GET /machines/<runner name>/log` request
+    ///
+    /// The serial console log of the machine is streamed for as long as the
+    /// machine runs, or returned as-is if the machine has already stopped.
+    /// With `?follow=false` only the log written so far is returned.
+    /// Requests are authorized using the `log_token` of the repository the
+    /// machine belongs to or the `host.admin_token`.
+    pub async fn handle(&self, request: Request<Incoming>) -> anyhow::Result<Response<ApiBody>> {
+        if request.method() != Method::GET {
+            return Ok(text_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "Only GET is supported\n",
+            ));
+        }
+
+        // input: "/machines/<runner name>/log"
+        // split: ["", "machines", "<runner name>", "log"]
+        let components: Vec<&str> = request.uri().path().split('/').collect();
+
+        let runner_name = match components.as_slice() {
+            ["", "machines", runner_name, "log"] => *runner_name,
+            _ => return Ok(text_response(StatusCode::NOT_FOUND, "File not found\n")),
+        };
+
+        // Use the same response for machines that do not exist and machines
+        // the request is not authorized for to not leak which machines exist.
+        let not_found = || text_response(StatusCode::NOT_FOUND, "No accessible machine\n");
+
+        let follow = request.uri().query() != Some("follow=false");
+
+        let log_path = {
+            let cfg = self.config.get();
+
+            let (triplet, run_dir) = match find_run_dir(&cfg.host.base_dir, runner_name)? {
+                Some(found) => found,
+                None => return Ok(not_found()),
+            };
+
+            let log_token = cfg
+                .repositories
+                .get(triplet.owner())
+                .and_then(|repos| repos.get(triplet.repository()))
+                .and_then(|repo| repo.log_token.as_deref());
+
+            let admin_token = cfg.host.admin_token.as_deref();
+
+            let authorized = [log_token, admin_token]
+                .into_iter()
+                .flatten()
+                .any(|valid_token| token_matches(valid_token, token(&request)));
+
+            if !authorized {
+                return Ok(not_found());
+            }
+
+            run_dir.join("log.txt")
+        };
+
+        let (sender, body) = Channel::new(4);
+
+        let machine_manager = self.machine_manager.clone();
+        let runner_name = runner_name.to_owned();
+
+        tokio::spawn(async move {
+            let res = send_log(log_path, sender, &machine_manager, &runner_name, follow).await;
+
+            if let Err(e) = res {
+                debug!("Stopped streaming the log of {runner_name}: {e}");
+            }
+        });
+
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(Either::Right(body))
+            .unwrap();
+
+        Ok(response)
+    }
+}
+
+/// Send the content of `log_path` and (if `follow` is set) anything written to it while the machine runs
+async fn send_log(
+    log_path: PathBuf,
+    mut sender: Sender<Bytes>,
+    machine_manager: &MachineManager,
+    runner_name: &str,
+    follow: bool,
+) -> anyhow::Result<()> {
+    let mut file = None;
+    let mut buf = vec![0; CHUNK_SIZE];
+
+    loop {
+        // Check if the machine is still running before reading from the file,
+        // so that we do not miss anything it wrote before stopping.
+        let running = follow
+            && machine_manager
+                .machine_by_runner_name(runner_name)
+                .is_some();
+
+        // The log file is only created once qemu starts.
+        if file.is_none() {
+            file = match File::open(&log_path).await {
+                Ok(file) => Some(file),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound && running => None,
+                Err(e) => return Err(e.into()),
+            };
+        }
+
+        if let Some(file) = file.as_mut() {
+            loop {
+                let len = file.read(&mut buf).await?;
+
+                if len == 0 {
+                    break;
+                }
+
+                trace!("Sending {len} bytes of the log of {runner_name}");
+
+                sender
+                    .send_data(Bytes::copy_from_slice(&buf[..len]))
+                    .await?;
+            }
+        }
+
+        if !running {
+            return Ok(());
+        }
+
+        tokio::time::sleep(FOLLOW_INTERVAL).await;
+    }
+}
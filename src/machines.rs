@@ -1,10 +1,27 @@
 mod config_fs;
+mod daemon;
+mod egress;
+mod generations;
 mod mac_pool;
 mod machine;
 mod manager;
+mod network;
+mod notify;
+mod provenance;
+mod retention;
 mod run_dir;
+mod sparsify;
+mod ssh_access;
+mod tpm;
 mod triplet;
 
-pub use machine::Artifact;
-pub use manager::Manager;
+pub use generations::{
+    info as image_info, print_info as print_image_info, print_list as image_list,
+    print_provenance as image_show, rollback as image_rollback,
+};
+pub use machine::{Artifact, Machine};
+pub use manager::{Manager, ManagerStatus};
+pub use run_dir::find as find_run_dir;
+pub use ssh_access::{jump as ssh_jump, print_authorized_keys};
+pub use tpm::{init as tpm_init, TpmInitOptions};
 pub use triplet::{OwnerAndRepo, Triplet};
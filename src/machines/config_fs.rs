@@ -1,5 +1,6 @@
+use std::collections::BTreeMap;
 use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use fatfs::{format_volume, FileSystem, FormatVolumeOptions, FsOptions};
 use log::warn;
@@ -25,6 +26,9 @@ impl ConfigFs {
     ///   This means that only plain text files may be present in the `template_path`.
     /// * `substitutions` - Pairs of from -> to text replacements to perform on all files
     ///   in the `template_path`.
+    /// * `overrides` - Pairs of file names and paths of files to place into the image
+    ///   instead of the ones with the same name in the `template_path`.
+    ///   These are subject to the same `substitutions`.
     ///
     /// The image file is removed from the file system as soon as the return value is dropped.
     pub fn new(
@@ -33,6 +37,7 @@ impl ConfigFs {
         label: &str,
         template_path: PathBuf,
         substitutions: &[(&str, &str)],
+        overrides: &[(&str, &Path)],
     ) -> std::io::Result<Self> {
         let filesystem = {
             let mut image = std::fs::File::create_new(&path)?;
@@ -54,7 +59,7 @@ impl ConfigFs {
             FileSystem::new(image, FsOptions::new())?
         };
 
-        let root_dir = filesystem.root_dir();
+        let mut files = BTreeMap::new();
 
         for entry in std::fs::read_dir(template_path)? {
             let entry = entry?;
@@ -68,9 +73,9 @@ impl ConfigFs {
                 continue;
             }
 
-            let name = match file_name.to_str() {
-                Some(name) => name,
-                None => {
+            let name = match file_name.into_string() {
+                Ok(name) => name,
+                Err(file_name) => {
                     warn!(
                         "Ignoring file with non-utf8 name '{}' during assembly of config fs",
                         file_name.to_string_lossy()
@@ -79,6 +84,16 @@ impl ConfigFs {
                 }
             };
 
+            files.insert(name, path);
+        }
+
+        for (name, path) in overrides {
+            files.insert(name.to_string(), path.to_path_buf());
+        }
+
+        let root_dir = filesystem.root_dir();
+
+        for (name, path) in files {
             // Replace placeholders in the file, like <REPO_OWNER> or <JITCONFIG>
             // with values provided in `substitutions`.
             // This is not an efficient or elegant solution, but a simple one.
@@ -91,7 +106,7 @@ impl ConfigFs {
                 content = content.replace(&format!("<{from}>"), to);
             }
 
-            let mut file = root_dir.create_file(name)?;
+            let mut file = root_dir.create_file(&name)?;
             file.truncate()?;
             file.write_all(content.as_bytes())?;
         }
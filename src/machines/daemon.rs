@@ -0,0 +1,249 @@
+use std::fs::File;
+use std::future::{poll_fn, Future};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::task::Poll;
+use std::time::{Duration, Instant};
+
+use log::{debug, error};
+use tokio::process::{Child, Command};
+
+// Daemons should create their control socket within a few seconds.
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(10);
+const SOCKET_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Daemons like virtiofsd exit by themselves once qemu closes its connection
+// during a normal shutdown, so qemu gets some time to exit as well before the
+// exit of a daemon is considered an error.
+const QEMU_EXIT_GRACE: Duration = Duration::from_secs(5);
+
+/// A helper process that is required by a qemu process, like `virtiofsd`
+///
+/// The daemon is killed and its socket removed once this is dropped.
+pub(super) struct Daemon {
+    name: String,
+    child: Child,
+    socket: PathBuf,
+}
+
+impl Daemon {
+    /// Spawn a daemon and wait for it to create its socket
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - A name for the daemon to use in log messages.
+    /// * `cmd` - The command to run. Its output is redirected to `log`.
+    /// * `socket` - The unix domain socket the daemon creates once it is ready
+    ///   to accept connections.
+    /// * `log` - The file to write the output of the daemon to.
+    pub(super) async fn spawn(
+        name: impl ToString,
+        mut cmd: Command,
+        socket: PathBuf,
+        log: &Path,
+    ) -> std::io::Result<Self> {
+        let name = name.to_string();
+
+        // Remove leftovers from a previous run that would make us believe
+        // the daemon is ready right away.
+        match std::fs::remove_file(&socket) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        let stdout = File::create(log)?;
+        let stderr = stdout.try_clone()?;
+
+        let child = cmd
+            .kill_on_drop(true)
+            .stdin(Stdio::null())
+            .stdout(stdout)
+            .stderr(stderr)
+            .spawn()?;
+
+        let mut daemon = Self {
+            name,
+            child,
+            socket,
+        };
+
+        let start = Instant::now();
+
+        loop {
+            if let Some(status) = daemon.child.try_wait()? {
+                let msg = format!(
+                    "{} exited with {status} before it was ready. See {} for details",
+                    daemon.name,
+                    log.display()
+                );
+
+                return Err(std::io::Error::other(msg));
+            }
+
+            if daemon.socket.try_exists()? {
+                debug!("{} is ready after {:?}", daemon.name, start.elapsed());
+                return Ok(daemon);
+            }
+
+            if start.elapsed() > SOCKET_TIMEOUT {
+                let msg = format!(
+                    "{} did not create {} in time",
+                    daemon.name,
+                    daemon.socket.display()
+                );
+
+                return Err(std::io::Error::other(msg));
+            }
+
+            tokio::time::sleep(SOCKET_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Wait for the daemon to exit and return an error describing the exit
+    ///
+    /// Returns `None` if the daemon exited cleanly.
+    /// Daemons like swtpm do so when qemu tells them to while it shuts down,
+    /// which may happen before qemu itself exits.
+    async fn exited(&mut self) -> Option<std::io::Error> {
+        let msg = match self.child.wait().await {
+            Ok(status) if status.success() => {
+                debug!("{} exited", self.name);
+                return None;
+            }
+            Ok(status) => format!("{} exited unexpectedly with {status}", self.name),
+            Err(e) => format!("Failed to wait for {}: {e}", self.name),
+        };
+
+        Some(std::io::Error::other(msg))
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        // The process itself is killed by `kill_on_drop`.
+        // Remove the socket so it does not linger around in the run dir.
+        match std::fs::remove_file(&self.socket) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => error!("Failed to remove {}: {e}", self.socket.display()),
+        }
+    }
+}
+
+/// Wait for the first of `daemons` to exit with an error
+///
+/// Never completes if `daemons` is empty or all of them exit cleanly.
+async fn first_exited(daemons: &mut [Daemon]) -> std::io::Error {
+    let mut exits: Vec<_> = daemons
+        .iter_mut()
+        .map(|daemon| Some(Box::pin(daemon.exited())))
+        .collect();
+
+    poll_fn(|cx| {
+        for slot in exits.iter_mut() {
+            let exit = match slot {
+                Some(exit) => exit,
+                None => continue,
+            };
+
+            match exit.as_mut().poll(cx) {
+                Poll::Ready(Some(err)) => return Poll::Ready(err),
+                // Clean exits are left to qemu to judge.
+                Poll::Ready(None) => *slot = None,
+                Poll::Pending => {}
+            }
+        }
+
+        Poll::Pending
+    })
+    .await
+}
+
+/// Wait for `qemu` to exit while making sure that `daemons` keep running
+///
+/// Returns the exit status of qemu, even if a daemon exited shortly before it
+/// during the shutdown of qemu.
+/// Daemons that exit cleanly are never an error by themselves.
+/// Returns an error if a daemon failed while qemu kept running.
+pub(super) async fn supervise(
+    qemu: &mut Child,
+    daemons: &mut [Daemon],
+) -> std::io::Result<ExitStatus> {
+    let err = tokio::select! {
+        status = qemu.wait() => return status,
+        err = first_exited(daemons) => err,
+    };
+
+    match tokio::time::timeout(QEMU_EXIT_GRACE, qemu.wait()).await {
+        Ok(status) => status,
+        Err(_) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, remove_dir_all};
+
+    use tokio::process::Command;
+
+    use super::{supervise, Daemon};
+
+    #[tokio::test]
+    async fn daemon_exits_during_shutdown() {
+        let dir = std::env::temp_dir().join(format!("forrest-test-daemon-{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+
+        let socket = dir.join("daemon.sock");
+
+        // Like virtiofsd once qemu closes its connection while shutting down
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(["-c", "touch \"$0\"; sleep 0.2; exit 0"])
+            .arg(&socket);
+
+        let daemon = Daemon::spawn("daemon", cmd, socket, &dir.join("daemon.log"))
+            .await
+            .unwrap();
+
+        let mut qemu = Command::new("/bin/sh")
+            .args(["-c", "sleep 0.5; exit 0"])
+            .spawn()
+            .unwrap();
+
+        let status = supervise(&mut qemu, &mut [daemon]).await.unwrap();
+
+        let _ = remove_dir_all(&dir);
+
+        assert!(status.success());
+    }
+
+    #[tokio::test]
+    async fn daemon_exits_cleanly() {
+        let dir = std::env::temp_dir().join(format!("forrest-test-clean-{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+
+        let socket = dir.join("daemon.sock");
+
+        // Like swtpm once qemu sent it CMD_SHUTDOWN, while qemu itself fails
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(["-c", "touch \"$0\"; sleep 0.2; exit 0"])
+            .arg(&socket);
+
+        let daemon = Daemon::spawn("daemon", cmd, socket, &dir.join("daemon.log"))
+            .await
+            .unwrap();
+
+        let mut qemu = Command::new("/bin/sh")
+            .args(["-c", "sleep 0.5; exit 3"])
+            .spawn()
+            .unwrap();
+
+        let status = supervise(&mut qemu, &mut [daemon]).await.unwrap();
+
+        let _ = remove_dir_all(&dir);
+
+        // The exit of qemu is reported, not the one of the daemon
+        assert_eq!(status.code(), Some(3));
+    }
+}
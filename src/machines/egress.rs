@@ -0,0 +1,359 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use log::{debug, info, warn};
+use tokio::io::{copy_bidirectional, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpStream, UnixListener, UnixStream};
+
+use crate::config::Egress;
+
+/// The address the egress proxy is available at from inside the guest
+pub(super) const EGRESS_PROXY_GUEST_ADDR: &str = "10.0.2.100:3128";
+
+// Requests with larger headers than this are rejected.
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+enum Rule {
+    Host(String),
+    Suffix(String),
+    Network(IpAddr, u32),
+}
+
+/// The set of destinations a machine may connect to
+pub(super) struct Allowlist {
+    // `None` means that any destination is allowed.
+    rules: Option<Vec<Rule>>,
+}
+
+impl Rule {
+    fn parse(entry: &str) -> Option<Self> {
+        if let Some((addr, prefix_len)) = entry.split_once('/') {
+            let addr: IpAddr = addr.parse().ok()?;
+            let prefix_len: u32 = prefix_len.parse().ok()?;
+
+            let max_len = match addr {
+                IpAddr::V4(_) => 32,
+                IpAddr::V6(_) => 128,
+            };
+
+            return (prefix_len <= max_len).then_some(Self::Network(addr, prefix_len));
+        }
+
+        if let Ok(addr) = entry.parse::<IpAddr>() {
+            let prefix_len = match addr {
+                IpAddr::V4(_) => 32,
+                IpAddr::V6(_) => 128,
+            };
+
+            return Some(Self::Network(addr, prefix_len));
+        }
+
+        let entry = entry.to_ascii_lowercase();
+
+        match entry.strip_prefix("*.") {
+            Some(suffix) => Some(Self::Suffix(format!(".{suffix}"))),
+            None => Some(Self::Host(entry)),
+        }
+    }
+
+    fn matches_host(&self, host: &str) -> bool {
+        match self {
+            Self::Host(name) => name == host,
+            Self::Suffix(suffix) => host.ends_with(suffix.as_str()),
+            Self::Network(..) => false,
+        }
+    }
+
+    fn matches_addr(&self, addr: IpAddr) -> bool {
+        let (network, prefix_len) = match self {
+            Self::Network(network, prefix_len) => (network, *prefix_len),
+            Self::Host(_) | Self::Suffix(_) => return false,
+        };
+
+        match (network, addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - prefix_len).unwrap_or(0);
+                u32::from(*network) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - prefix_len).unwrap_or(0);
+                u128::from(*network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Allowlist {
+    pub(super) fn new(egress: &Egress) -> std::io::Result<Self> {
+        let rules = match &egress.allow {
+            Some(allow) => allow,
+            None => return Ok(Self { rules: None }),
+        };
+
+        let rules = rules
+            .iter()
+            .map(|entry| {
+                Rule::parse(entry).ok_or_else(|| {
+                    std::io::Error::other(format!("Invalid egress allowlist entry '{entry}'"))
+                })
+            })
+            .collect::<std::io::Result<_>>()?;
+
+        Ok(Self { rules: Some(rules) })
+    }
+
+    /// Is a connection to `host` allowed regardless of the address it resolves to?
+    fn allows_host(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+
+        match &self.rules {
+            Some(rules) => rules.iter().any(|rule| rule.matches_host(&host)),
+            None => true,
+        }
+    }
+
+    /// Is a connection to `addr` allowed?
+    fn allows_addr(&self, addr: IpAddr) -> bool {
+        match &self.rules {
+            Some(rules) => rules.iter().any(|rule| rule.matches_addr(addr)),
+            None => true,
+        }
+    }
+}
+
+/// Serve an HTTP proxy for a machine that only connects to allowed destinations
+///
+/// The guest reaches this proxy via a `guestfwd` rule in the qemu user mode
+/// network stack, which is otherwise restricted from accessing the outside world.
+/// The proxy supports `CONNECT` requests (used e.g. for HTTPS) and plain HTTP
+/// requests with an absolute URI.
+pub(super) async fn serve(
+    listener: UnixListener,
+    allowlist: Allowlist,
+    runner_name: String,
+) -> std::io::Result<()> {
+    let allowlist = Arc::new(allowlist);
+    let runner_name: Arc<str> = runner_name.into();
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+
+        let allowlist = allowlist.clone();
+        let runner_name = runner_name.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, &allowlist, &runner_name).await {
+                debug!("Egress proxy connection for {runner_name} failed: {e}");
+            }
+        });
+    }
+}
+
+/// Read the request head (everything up to the first empty line)
+async fn read_head(stream: &mut UnixStream) -> std::io::Result<Vec<u8>> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > MAX_HEAD_SIZE {
+            return Err(std::io::Error::other("Request head is too large"));
+        }
+
+        let len = stream.read(&mut buf).await?;
+
+        if len == 0 {
+            return Err(std::io::Error::other(
+                "Connection closed before end of head",
+            ));
+        }
+
+        head.extend_from_slice(&buf[..len]);
+    }
+
+    Ok(head)
+}
+
+/// Get the host and port to connect to from a request line
+///
+/// Returns the host, port and whether this is a `CONNECT` request.
+fn destination(request_line: &str) -> Option<(String, u16, bool)> {
+    let mut components = request_line.split_ascii_whitespace();
+
+    let method = components.next()?;
+    let target = components.next()?;
+
+    let (authority, default_port, is_connect) = if method == "CONNECT" {
+        (target, None, true)
+    } else {
+        let rest = target.strip_prefix("http://")?;
+        let authority = rest.split('/').next()?;
+
+        (authority, Some(80), false)
+    };
+
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => (host, port.parse().ok()?),
+        _ => (authority, default_port?),
+    };
+
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    if host.is_empty() {
+        return None;
+    }
+
+    Some((host.to_owned(), port, is_connect))
+}
+
+async fn handle(
+    mut stream: UnixStream,
+    allowlist: &Allowlist,
+    runner_name: &str,
+) -> std::io::Result<()> {
+    let head = read_head(&mut stream).await?;
+
+    let request_line = {
+        let end = head.iter().position(|b| *b == b'\r').unwrap_or(head.len());
+        String::from_utf8_lossy(&head[..end]).into_owned()
+    };
+
+    let (host, port, is_connect) = match destination(&request_line) {
+        Some(dst) => dst,
+        None => {
+            warn!("Egress proxy for {runner_name} got malformed request: {request_line}");
+
+            stream
+                .write_all(b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n")
+                .await?;
+
+            return Ok(());
+        }
+    };
+
+    // Allow the connection if the host name is allowed or if it resolves
+    // to an address in an allowed network.
+    let host_allowed = allowlist.allows_host(&host);
+
+    let addrs: Vec<SocketAddr> = lookup_host((host.as_str(), port))
+        .await
+        .map(|addrs| {
+            addrs
+                .filter(|addr| host_allowed || allowlist.allows_addr(addr.ip()))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    if addrs.is_empty() {
+        warn!("Denied egress connection from {runner_name} to {host}:{port}");
+
+        stream
+            .write_all(b"HTTP/1.1 403 Forbidden\r\nConnection: close\r\n\r\n")
+            .await?;
+
+        return Ok(());
+    }
+
+    let mut upstream = match TcpStream::connect(addrs.as_slice()).await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            info!("Egress connection from {runner_name} to {host}:{port} failed: {e}");
+
+            stream
+                .write_all(b"HTTP/1.1 502 Bad Gateway\r\nConnection: close\r\n\r\n")
+                .await?;
+
+            return Ok(());
+        }
+    };
+
+    debug!("Allowed egress connection from {runner_name} to {host}:{port}");
+
+    if is_connect {
+        stream
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await?;
+
+        // The client may have sent data right after the request head,
+        // which belongs to the tunneled connection.
+        let head_end = head
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .map(|pos| pos + 4)
+            .unwrap_or(head.len());
+
+        upstream.write_all(&head[head_end..]).await?;
+    } else {
+        // HTTP/1.1 servers have to accept requests with absolute URIs,
+        // so we can forward the request as is.
+        upstream.write_all(&head).await?;
+    }
+
+    copy_bidirectional(&mut stream, &mut upstream).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{destination, Allowlist};
+    use crate::config::Egress;
+
+    #[test]
+    fn allowlist() {
+        let egress = Egress {
+            allow: Some(vec![
+                "github.com".into(),
+                "*.githubusercontent.com".into(),
+                "10.0.0.0/8".into(),
+                "2001:db8::/32".into(),
+                "192.168.1.1".into(),
+            ]),
+        };
+
+        let allowlist = Allowlist::new(&egress).unwrap();
+
+        assert!(allowlist.allows_host("github.com"));
+        assert!(allowlist.allows_host("GitHub.com"));
+        assert!(allowlist.allows_host("objects.githubusercontent.com"));
+        assert!(!allowlist.allows_host("githubusercontent.com"));
+        assert!(!allowlist.allows_host("api.github.com"));
+        assert!(!allowlist.allows_host("example.com"));
+
+        assert!(allowlist.allows_addr("10.1.2.3".parse().unwrap()));
+        assert!(!allowlist.allows_addr("11.1.2.3".parse().unwrap()));
+        assert!(allowlist.allows_addr("192.168.1.1".parse().unwrap()));
+        assert!(!allowlist.allows_addr("192.168.1.2".parse().unwrap()));
+        assert!(allowlist.allows_addr("2001:db8::1".parse().unwrap()));
+        assert!(!allowlist.allows_addr("2001:db9::1".parse().unwrap()));
+
+        let allow_all = Allowlist::new(&Egress { allow: None }).unwrap();
+
+        assert!(allow_all.allows_host("example.com"));
+        assert!(allow_all.allows_addr("1.1.1.1".parse().unwrap()));
+
+        let invalid = Egress {
+            allow: Some(vec!["10.0.0.0/33".into()]),
+        };
+
+        assert!(Allowlist::new(&invalid).is_err());
+    }
+
+    #[test]
+    fn request_destination() {
+        assert_eq!(
+            destination("CONNECT github.com:443 HTTP/1.1"),
+            Some(("github.com".into(), 443, true))
+        );
+        assert_eq!(
+            destination("GET http://deb.debian.org/debian/dists HTTP/1.1"),
+            Some(("deb.debian.org".into(), 80, false))
+        );
+        assert_eq!(
+            destination("GET http://[2001:db8::1]:8080/ HTTP/1.1"),
+            Some(("2001:db8::1".into(), 8080, false))
+        );
+        assert_eq!(destination("GET /index.html HTTP/1.1"), None);
+        assert_eq!(destination("CONNECT github.com HTTP/1.1"), None);
+    }
+}
@@ -0,0 +1,1131 @@
+use std::fs::{read_dir, read_link, remove_file, rename, symlink_metadata};
+use std::io::ErrorKind;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use chrono::{DateTime, Local};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use super::provenance::Provenance;
+use super::triplet::Triplet;
+use crate::config::{ConfigFile, PersistConflictPolicy};
+
+// The files that make up a generation of a machine type.
+// The disk image is always present, the others only if the machine uses them.
+const IMAGE_EXT: &str = "img";
+const EFIVARS_EXT: &str = "efivars";
+const TPM_STATE_EXT: &str = "swtpm";
+const PROVENANCE_EXT: &str = "json";
+const EXTENSIONS: &[&str] = &[IMAGE_EXT, EFIVARS_EXT, TPM_STATE_EXT, PROVENANCE_EXT];
+
+// Points to the disk image of a generation that still has to pass a smoke test
+// before it becomes the current generation.
+const CANDIDATE_EXT: &str = "candidate";
+
+// Appended (along with a timestamp) to the files of generations that
+// were quarantined because machines failed to start from them.
+const QUARANTINE_MARKER: &str = ".broken-";
+
+/// The persisted disk images (and accompanying files) of a machine type
+///
+/// Each time a job persists its disk image a new generation is created as
+/// `<machine>.<n>.img` (plus `<machine>.<n>.efivars` and `<machine>.<n>.swtpm`
+/// if present) with its provenance in `<machine>.<n>.json`.
+/// The current generation is pointed to by the `<machine>.img`, `<machine>.efivars`,
+/// `<machine>.swtpm` and `<machine>.json` symlinks, which are what new machines start from.
+/// If a `promotion` policy is configured a newly persisted generation only becomes
+/// a candidate, pointed to by `<machine>.candidate`, until it passed a smoke test.
+/// Quarantined generations are renamed to e.g. `<machine>.<n>.img.broken-<timestamp>`.
+pub(super) struct Generations {
+    dir: PathBuf,
+    machine_name: String,
+}
+
+/// A persisted generation of a machine image, as shown by `image list`
+#[derive(Serialize, Deserialize)]
+pub struct GenerationInfo {
+    pub generation: u32,
+    /// New machines start from this generation
+    pub current: bool,
+    /// This generation still has to pass a smoke test
+    pub candidate: bool,
+    pub modified: String,
+    /// The size of the disk image in bytes
+    pub size: u64,
+    /// The commit the image was built from
+    pub head_sha: Option<String>,
+    /// The other files that belong to the generation, like `efivars` or `swtpm`
+    pub extras: Vec<String>,
+}
+
+/// A generation that was quarantined at `quarantined` because machines failed to start from it
+#[derive(Serialize, Deserialize)]
+pub struct QuarantinedInfo {
+    pub generation: u32,
+    pub quarantined: String,
+}
+
+/// The image generations of a machine type, newest first
+#[derive(Serialize, Deserialize)]
+pub struct ImageInfo {
+    pub triplet: String,
+    pub generations: Vec<GenerationInfo>,
+    pub quarantined: Vec<QuarantinedInfo>,
+}
+
+/// Replace `link` with a symlink to the file `target` in the same directory
+///
+/// The symlink is created under a temporary name first and then moved into place,
+/// so that `link` always points to a complete generation.
+fn replace_symlink(link: &Path, target: &str) -> std::io::Result<()> {
+    let mut tmp_name = link.file_name().unwrap().to_os_string();
+    tmp_name.push(".tmp");
+
+    let tmp_link = link.with_file_name(tmp_name);
+
+    match remove_file(&tmp_link) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    symlink(target, &tmp_link)?;
+    rename(&tmp_link, link)
+}
+
+fn fmt_generation(generation: Option<u32>) -> String {
+    match generation {
+        Some(generation) => generation.to_string(),
+        None => "the base image".to_owned(),
+    }
+}
+
+fn format_time(time: SystemTime) -> String {
+    let time: DateTime<Local> = time.into();
+
+    time.format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+impl Generations {
+    pub(super) fn new(triplet: &Triplet, base_dir: &Path) -> Self {
+        let image_pointer = triplet.machine_image_path(base_dir);
+        let dir = image_pointer.parent().unwrap().to_owned();
+
+        Self {
+            dir,
+            machine_name: triplet.machine_name().to_owned(),
+        }
+    }
+
+    fn file_name(&self, generation: u32, ext: &str) -> String {
+        format!("{}.{generation}.{ext}", self.machine_name)
+    }
+
+    /// Parse the generation from a file name like `<machine>.<n>.<ext>`
+    fn generation_of(&self, name: &str, ext: &str) -> Option<u32> {
+        name.strip_prefix(&self.machine_name)
+            .and_then(|name| name.strip_prefix('.'))
+            .and_then(|name| name.strip_suffix(ext))
+            .and_then(|name| name.strip_suffix('.'))
+            .filter(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+            .and_then(|n| n.parse().ok())
+    }
+
+    /// The generation of the disk image at `path`
+    ///
+    /// Returns `None` for paths that are not the disk image of a generation.
+    pub(super) fn image_generation(&self, path: &Path) -> Option<u32> {
+        match path.parent() == Some(&self.dir) {
+            true => self.generation_of(path.file_name()?.to_str()?, IMAGE_EXT),
+            false => None,
+        }
+    }
+
+    fn pointer(&self, ext: &str) -> PathBuf {
+        self.dir.join(format!("{}.{ext}", self.machine_name))
+    }
+
+    /// The path of a file of `generation`, regardless of it existing or not
+    pub(super) fn path(&self, generation: u32, ext: &str) -> PathBuf {
+        self.dir.join(self.file_name(generation, ext))
+    }
+
+    /// The generation a pointer currently points to
+    ///
+    /// Returns `None` if the pointer does not exist or is a plain file,
+    /// as created by older versions of Forrest.
+    fn pointed_to(&self, ext: &str) -> std::io::Result<Option<u32>> {
+        let target = match read_link(self.pointer(ext)) {
+            Ok(target) => target,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) if e.kind() == ErrorKind::InvalidInput => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        Ok(target
+            .to_str()
+            .and_then(|name| self.generation_of(name, ext)))
+    }
+
+    /// The generation new machines currently start from
+    pub(super) fn current(&self) -> std::io::Result<Option<u32>> {
+        self.pointed_to(IMAGE_EXT)
+    }
+
+    /// The generation that waits for a smoke test before it can be promoted
+    pub(super) fn candidate(&self) -> std::io::Result<Option<u32>> {
+        let target = match read_link(self.pointer(CANDIDATE_EXT)) {
+            Ok(target) => target,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let candidate = target
+            .to_str()
+            .and_then(|name| self.generation_of(name, IMAGE_EXT));
+
+        // The candidate may have been discarded or pruned in the meantime.
+        match candidate {
+            Some(candidate) if self.path(candidate, IMAGE_EXT).try_exists()? => Ok(Some(candidate)),
+            _ => Ok(None),
+        }
+    }
+
+    /// The names of all files in the machine dir
+    fn file_names(&self) -> std::io::Result<Vec<String>> {
+        let entries = match read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut names = Vec::new();
+
+        for entry in entries {
+            if let Ok(name) = entry?.file_name().into_string() {
+                names.push(name);
+            }
+        }
+
+        Ok(names)
+    }
+
+    /// All generations that have a disk image, sorted from oldest to newest
+    fn list(&self) -> std::io::Result<Vec<u32>> {
+        let mut generations: Vec<u32> = self
+            .file_names()?
+            .iter()
+            .filter_map(|name| self.generation_of(name, IMAGE_EXT))
+            .collect();
+
+        generations.sort();
+
+        Ok(generations)
+    }
+
+    /// The newest generation, including candidates that were not promoted yet
+    pub(super) fn newest(&self) -> std::io::Result<Option<u32>> {
+        Ok(self.list()?.last().copied())
+    }
+
+    /// Check if the image of a run may be persisted under the `policy`
+    ///
+    /// `newest_at_start` is the newest generation at the time the machine was
+    /// started and `base` the generation it started from.
+    /// If another run persisted a generation in the meantime the runs conflict.
+    /// Generations that were quarantined or discarded in the meantime
+    /// (which makes an older generation the newest one) do not conflict.
+    /// Returns why the image must not be persisted in this case,
+    /// or `None` if it may be persisted.
+    pub(super) fn persist_conflict(
+        &self,
+        policy: PersistConflictPolicy,
+        newest_at_start: Option<u32>,
+        base: Option<u32>,
+    ) -> std::io::Result<Option<String>> {
+        let newest = match self.newest()? {
+            // Generation numbers are never re-used, so only a newer generation
+            // can have been persisted while the machine ran.
+            Some(newest) if Some(newest) > newest_at_start => newest,
+            _ => return Ok(None),
+        };
+
+        let reason = match policy {
+            PersistConflictPolicy::Last => {
+                info!(
+                    "Generation {newest} of {} was persisted while the machine ran. Persisting anyways (last_wins)",
+                    self.machine_name
+                );
+                None
+            }
+            PersistConflictPolicy::First => Some(format!(
+                "generation {newest} was persisted while the machine ran (first_wins)"
+            )),
+            PersistConflictPolicy::NewestBase => {
+                let newest_base = Provenance::read(&self.path(newest, PROVENANCE_EXT))?
+                    .and_then(|provenance| provenance.base_generation);
+
+                // Images that did not start from a generation of this machine type
+                // (e.g. from the base image) are based on the oldest possible image.
+                match base >= newest_base {
+                    true => None,
+                    false => Some(format!(
+                        "generation {newest} was persisted while the machine ran and is based on a newer generation ({}) than this run ({}) (newest_base_wins)",
+                        fmt_generation(newest_base),
+                        fmt_generation(base),
+                    )),
+                }
+            }
+        };
+
+        Ok(reason)
+    }
+
+    /// All quarantined generations and when they were quarantined,
+    /// sorted from oldest to newest
+    fn quarantined(&self) -> std::io::Result<Vec<(String, u32)>> {
+        let mut quarantined: Vec<(String, u32)> = self
+            .file_names()?
+            .iter()
+            .filter_map(|name| {
+                let (name, timestamp) = name.split_once(QUARANTINE_MARKER)?;
+                let generation = self.generation_of(name, IMAGE_EXT)?;
+
+                Some((timestamp.to_owned(), generation))
+            })
+            .collect();
+
+        quarantined.sort();
+
+        Ok(quarantined)
+    }
+
+    /// The number the next persisted generation gets
+    ///
+    /// Numbers of quarantined generations are not re-used to not confuse them
+    /// with the new generation.
+    fn next(&self) -> std::io::Result<u32> {
+        let newest = self.list()?.last().copied();
+        let newest_quarantined = self.quarantined()?.iter().map(|(_, g)| *g).max();
+
+        Ok(match newest.max(newest_quarantined) {
+            Some(n) => n + 1,
+            None => 0,
+        })
+    }
+
+    /// Resolve the pointer for a kind of file to the file of the current generation
+    ///
+    /// This makes sure that a machine keeps using the same generation, even if
+    /// a new one is persisted or rolled back to while it is being set up.
+    /// Plain files and missing pointers are returned as-is.
+    fn resolve(&self, ext: &str) -> std::io::Result<PathBuf> {
+        let pointer = self.pointer(ext);
+
+        Ok(match self.pointed_to(ext)? {
+            Some(generation) => self.dir.join(self.file_name(generation, ext)),
+            None => pointer,
+        })
+    }
+
+    /// The disk image of the current generation
+    pub(super) fn image(&self) -> std::io::Result<PathBuf> {
+        self.resolve(IMAGE_EXT)
+    }
+
+    /// The UEFI variables of the current generation
+    pub(super) fn efivars(&self) -> std::io::Result<PathBuf> {
+        self.resolve(EFIVARS_EXT)
+    }
+
+    /// The TPM state of the current generation
+    pub(super) fn tpm_state(&self) -> std::io::Result<PathBuf> {
+        self.resolve(TPM_STATE_EXT)
+    }
+
+    /// Point the pointers to the files of `generation`
+    ///
+    /// Pointers for files that the generation does not have are removed if
+    /// they point to another generation, so that machines do not combine the
+    /// image of one generation with e.g. the TPM state of another.
+    /// Plain files, like a TPM state that was set up using `tpm init`, are kept.
+    fn point_to(&self, generation: u32) -> std::io::Result<()> {
+        // Update the disk image pointer last, as it is the one that
+        // determines the current generation.
+        for ext in EXTENSIONS.iter().rev() {
+            let name = self.file_name(generation, ext);
+            let pointer = self.pointer(ext);
+
+            if self.dir.join(&name).try_exists()? {
+                replace_symlink(&pointer, &name)?;
+            } else if pointer.is_symlink() {
+                remove_file(&pointer)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Turn plain files created by older versions of Forrest into generation 0
+    pub(super) fn migrate(&self) -> std::io::Result<()> {
+        let image_pointer = self.pointer(IMAGE_EXT);
+
+        let is_plain_file = match symlink_metadata(&image_pointer) {
+            Ok(meta) => meta.is_file(),
+            Err(e) if e.kind() == ErrorKind::NotFound => false,
+            Err(e) => return Err(e),
+        };
+
+        if !is_plain_file || !self.list()?.is_empty() {
+            return Ok(());
+        }
+
+        for ext in EXTENSIONS {
+            let pointer = self.pointer(ext);
+
+            match symlink_metadata(&pointer) {
+                Ok(meta) if meta.is_file() => {
+                    rename(&pointer, self.dir.join(self.file_name(0, ext)))?
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.point_to(0)?;
+
+        info!(
+            "Migrated the machine image of {} to generation 0",
+            self.machine_name
+        );
+
+        Ok(())
+    }
+
+    /// Make the files of a finished run the new current (or candidate) generation
+    ///
+    /// `files` are pairs of the kind of file (its extension) and its path in the run dir.
+    /// The `provenance` is written to a sidecar file along with the generation number.
+    /// If `candidate` is set the generation has to be promoted before machines
+    /// start from it, otherwise it becomes the current generation right away.
+    /// Only the newest `keep` generations are retained.
+    pub(super) fn persist(
+        &self,
+        files: &[(&str, &Path)],
+        keep: u32,
+        provenance: &mut Provenance,
+        candidate: bool,
+    ) -> std::io::Result<u32> {
+        std::fs::create_dir_all(&self.dir)?;
+
+        self.migrate()?;
+
+        let generation = self.next()?;
+
+        for (ext, path) in files {
+            let gen_path = self.dir.join(self.file_name(generation, ext));
+
+            match rename(path, &gen_path) {
+                Ok(()) => info!("Persisted {} as {}", path.display(), gen_path.display()),
+                // Only the disk image is required, the other files are optional.
+                Err(e) if e.kind() == ErrorKind::NotFound && *ext != IMAGE_EXT => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        provenance.generation = Some(generation);
+        provenance.write(&self.dir.join(self.file_name(generation, PROVENANCE_EXT)))?;
+
+        match candidate {
+            // A newer candidate replaces an older one that was not tested yet.
+            true => replace_symlink(
+                &self.pointer(CANDIDATE_EXT),
+                &self.file_name(generation, IMAGE_EXT),
+            )?,
+            false => self.point_to(generation)?,
+        }
+
+        if let Err(e) = self.prune(keep) {
+            error!(
+                "Failed to remove old generations of {}: {e}",
+                self.machine_name
+            );
+        }
+
+        Ok(generation)
+    }
+
+    /// Make a candidate that passed its smoke test the current generation
+    ///
+    /// Returns `false` if a newer generation is already the current one,
+    /// in which case the pointers are left alone.
+    pub(super) fn promote(&self, generation: u32) -> std::io::Result<bool> {
+        if self.candidate()? == Some(generation) {
+            remove_file(self.pointer(CANDIDATE_EXT))?;
+        }
+
+        if self.current()?.is_some_and(|current| current > generation) {
+            return Ok(false);
+        }
+
+        self.point_to(generation)?;
+
+        Ok(true)
+    }
+
+    /// Remove a candidate that failed its smoke test
+    pub(super) fn discard(&self, generation: u32) -> std::io::Result<()> {
+        if self.candidate()? == Some(generation) {
+            remove_file(self.pointer(CANDIDATE_EXT))?;
+        }
+
+        for ext in EXTENSIONS {
+            // Do not pull the rug out from under the pointers,
+            // e.g. if the candidate was rolled back to manually.
+            if self.pointed_to(ext)? == Some(generation) {
+                continue;
+            }
+
+            match remove_file(self.path(generation, ext)) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Move the files of `generation` out of the way because machines fail to start from it
+    ///
+    /// If `generation` was the current generation the generation before it
+    /// becomes the current one, or the pointers are removed if there is none,
+    /// so that new machines start from the base image again.
+    /// Only the newest `keep` quarantined generations are retained.
+    /// Returns the generation new machines start from now.
+    pub(super) fn quarantine(&self, generation: u32, keep: u32) -> std::io::Result<Option<u32>> {
+        let timestamp = Local::now().format("%Y%m%dT%H%M%S");
+
+        for ext in EXTENSIONS {
+            let path = self.dir.join(self.file_name(generation, ext));
+            let quarantined = self.dir.join(format!(
+                "{}{QUARANTINE_MARKER}{timestamp}",
+                self.file_name(generation, ext)
+            ));
+
+            match rename(&path, &quarantined) {
+                Ok(()) => info!(
+                    "Quarantined {} as {}",
+                    path.display(),
+                    quarantined.display()
+                ),
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        // Remove the pointers to the quarantined files.
+        // Pointers to files that are not part of the generation,
+        // like a TPM state that was set up using `tpm init`, are kept.
+        for ext in EXTENSIONS {
+            let pointer = self.pointer(ext);
+
+            if pointer.is_symlink() && !pointer.try_exists()? {
+                remove_file(&pointer)?;
+            }
+        }
+
+        // Machines may already start from a newer generation (or an older one
+        // after a rollback), which is kept in this case.
+        let fallback = match self.current()? {
+            Some(current) => Some(current),
+            None => self.list()?.into_iter().rfind(|other| *other < generation),
+        };
+
+        if let Some(fallback) = fallback {
+            self.point_to(fallback)?;
+        }
+
+        if let Err(e) = self.prune_quarantined(keep) {
+            error!(
+                "Failed to remove old quarantined generations of {}: {e}",
+                self.machine_name
+            );
+        }
+
+        Ok(fallback)
+    }
+
+    /// Remove all but the newest `keep` quarantined generations
+    fn prune_quarantined(&self, keep: u32) -> std::io::Result<()> {
+        let quarantined = self.quarantined()?;
+        let keep = keep as usize;
+
+        if quarantined.len() <= keep {
+            return Ok(());
+        }
+
+        for (timestamp, generation) in &quarantined[..(quarantined.len() - keep)] {
+            for ext in EXTENSIONS {
+                let path = self.dir.join(format!(
+                    "{}{QUARANTINE_MARKER}{timestamp}",
+                    self.file_name(*generation, ext)
+                ));
+
+                match remove_file(&path) {
+                    Ok(()) => info!("Removed quarantined generation {}", path.display()),
+                    Err(e) if e.kind() == ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove all but the newest `keep` generations
+    ///
+    /// Files that are still pointed to are never removed.
+    fn prune(&self, keep: u32) -> std::io::Result<()> {
+        let generations = self.list()?;
+        let keep = (keep as usize).max(1);
+
+        if generations.len() <= keep {
+            return Ok(());
+        }
+
+        let candidate = self.candidate()?;
+
+        for generation in &generations[..(generations.len() - keep)] {
+            if candidate == Some(*generation) {
+                continue;
+            }
+
+            for ext in EXTENSIONS {
+                if self.pointed_to(ext)? == Some(*generation) {
+                    continue;
+                }
+
+                let path = self.dir.join(self.file_name(*generation, ext));
+
+                match remove_file(&path) {
+                    Ok(()) => info!("Removed old generation {}", path.display()),
+                    Err(e) if e.kind() == ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn machine_generations(cfg: &ConfigFile, triplet: &Triplet) -> std::io::Result<Generations> {
+    let known = cfg
+        .repositories
+        .get(triplet.owner())
+        .and_then(|repos| repos.get(triplet.repository()))
+        .is_some_and(|repo| repo.machines.contains_key(triplet.machine_name()));
+
+    if !known {
+        let msg = format!("Machine {triplet} is not configured");
+        return Err(std::io::Error::other(msg));
+    }
+
+    Ok(Generations::new(triplet, &cfg.host.base_dir))
+}
+
+/// Get the generations of the machine image of a machine type
+pub fn info(cfg: &ConfigFile, triplet: &Triplet) -> std::io::Result<ImageInfo> {
+    let generations = machine_generations(cfg, triplet)?;
+
+    let current = generations.current()?;
+    let candidate = generations.candidate()?;
+    let list = generations.list()?;
+
+    let mut infos = Vec::new();
+
+    for generation in list.into_iter().rev() {
+        let image = generations
+            .dir
+            .join(generations.file_name(generation, IMAGE_EXT));
+        let meta = image.metadata()?;
+
+        let extras = [EFIVARS_EXT, TPM_STATE_EXT]
+            .iter()
+            .filter(|ext| {
+                generations
+                    .dir
+                    .join(generations.file_name(generation, ext))
+                    .exists()
+            })
+            .map(|ext| ext.to_string())
+            .collect();
+
+        let provenance = Provenance::read(
+            &generations
+                .dir
+                .join(generations.file_name(generation, PROVENANCE_EXT)),
+        )?;
+
+        infos.push(GenerationInfo {
+            generation,
+            current: current == Some(generation),
+            candidate: candidate == Some(generation),
+            modified: format_time(meta.modified()?),
+            size: meta.len(),
+            head_sha: provenance.and_then(|p| p.head_sha),
+            extras,
+        });
+    }
+
+    let quarantined = generations
+        .quarantined()?
+        .into_iter()
+        .rev()
+        .map(|(quarantined, generation)| QuarantinedInfo {
+            generation,
+            quarantined,
+        })
+        .collect();
+
+    Ok(ImageInfo {
+        triplet: triplet.to_string(),
+        generations: infos,
+        quarantined,
+    })
+}
+
+/// Print the generations of the machine images of a machine type
+pub fn print_list(cfg: &ConfigFile, triplet: &Triplet) -> std::io::Result<()> {
+    print_info(&info(cfg, triplet)?);
+
+    Ok(())
+}
+
+/// Print the generations of a machine type as returned by `info()`
+pub fn print_info(info: &ImageInfo) {
+    if info.generations.is_empty() {
+        println!("No generations of {} have been persisted yet", info.triplet);
+    }
+
+    for generation in &info.generations {
+        let marker = if generation.current {
+            "*"
+        } else if generation.candidate {
+            "?"
+        } else {
+            " "
+        };
+
+        // Show the commit the image was built from to make it easier
+        // to tell generations apart.
+        let head_sha: String = match &generation.head_sha {
+            Some(sha) => sha.chars().take(12).collect(),
+            None => "-".to_owned(),
+        };
+
+        println!(
+            "{marker} {:>4}  {}  {:>8} MiB  {head_sha:<12}  {}",
+            generation.generation,
+            generation.modified,
+            generation.size / (1024 * 1024),
+            generation.extras.join(" "),
+        );
+    }
+
+    for quarantined in &info.quarantined {
+        println!(
+            "! {:>4}  quarantined at {}",
+            quarantined.generation, quarantined.quarantined
+        );
+    }
+}
+
+/// Print the provenance of `generation` (or the current generation) of a machine type
+pub fn print_provenance(
+    cfg: &ConfigFile,
+    triplet: &Triplet,
+    generation: Option<u32>,
+) -> std::io::Result<()> {
+    let generations = machine_generations(cfg, triplet)?;
+
+    let generation = match generation
+        .map(Ok)
+        .or_else(|| generations.current().transpose())
+    {
+        Some(generation) => generation?,
+        None => {
+            let msg = format!("{triplet} does not have a current generation");
+            return Err(std::io::Error::other(msg));
+        }
+    };
+
+    let path = generations
+        .dir
+        .join(generations.file_name(generation, PROVENANCE_EXT));
+
+    match Provenance::read(&path)? {
+        Some(provenance) => println!("{}", provenance.to_json()?),
+        None => println!("Generation {generation} of {triplet} has no provenance information"),
+    }
+
+    Ok(())
+}
+
+/// Make `generation` (or the one before the current one) the current generation
+///
+/// Returns the generation new machines start from now.
+pub fn rollback(
+    cfg: &ConfigFile,
+    triplet: &Triplet,
+    generation: Option<u32>,
+) -> std::io::Result<u32> {
+    let generations = machine_generations(cfg, triplet)?;
+
+    let list = generations.list()?;
+    let current = generations.current()?;
+
+    let target = match (generation, current) {
+        (Some(generation), _) => generation,
+        (None, Some(current)) => list
+            .iter()
+            .copied()
+            .rfind(|generation| *generation < current)
+            .ok_or_else(|| {
+                std::io::Error::other(format!("There is no generation before {current}"))
+            })?,
+        (None, None) => {
+            let msg = format!("{triplet} does not have a current generation");
+            return Err(std::io::Error::other(msg));
+        }
+    };
+
+    if !list.contains(&target) {
+        let msg = format!("{triplet} does not have a generation {target}");
+        return Err(std::io::Error::other(msg));
+    }
+
+    generations.point_to(target)?;
+
+    Ok(target)
+}
+
+/// Quarantine a generation of the machine image of a machine type (by default the current one)
+///
+/// This is what happens automatically if machines repeatedly fail to start,
+/// but e.g. for images that start fine but break jobs.
+/// Returns the quarantined generation and the generation new machines start from now.
+pub(super) fn quarantine(
+    cfg: &ConfigFile,
+    triplet: &Triplet,
+    generation: Option<u32>,
+) -> std::io::Result<(u32, Option<u32>)> {
+    let generations = machine_generations(cfg, triplet)?;
+
+    let keep = cfg
+        .repositories
+        .get(triplet.owner())
+        .and_then(|repos| repos.get(triplet.repository()))
+        .and_then(|repo| repo.machines.get(triplet.machine_name()))
+        .map(|machine_config| machine_config.quarantine.keep)
+        .unwrap_or_default();
+
+    let target = match (generation, generations.current()?) {
+        (Some(generation), _) => generation,
+        (None, Some(current)) => current,
+        (None, None) => {
+            let msg = format!("{triplet} does not have a current generation");
+            return Err(std::io::Error::other(msg));
+        }
+    };
+
+    if !generations.list()?.contains(&target) {
+        let msg = format!("{triplet} does not have a generation {target}");
+        return Err(std::io::Error::other(msg));
+    }
+
+    let fallback = generations.quarantine(target, keep)?;
+
+    Ok((target, fallback))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, read_to_string, remove_dir_all, write};
+    use std::path::PathBuf;
+
+    use super::{Generations, Provenance};
+    use crate::config::PersistConflictPolicy;
+    use crate::machines::Triplet;
+
+    const PROVENANCE: &str = r#"{
+        "machine": "owner/repo/build",
+        "generation": null,
+        "runner_name": "forrest-build-rHCiNOhFdypjtnfj",
+        "run_id": 1234,
+        "job_id": 5678,
+        "workflow": "Build",
+        "head_branch": "main",
+        "head_sha": "0123456789abcdef0123456789abcdef01234567",
+        "config_hash": "",
+        "started": "2025-03-01T04:00:00+00:00",
+        "persisted": "2025-03-01T04:10:00+00:00",
+        "base_image": "/srv/forrest/images/debian-12-generic-amd64.raw",
+        "base_generation": null,
+        "base": null
+    }"#;
+
+    /// The image generations of a machine type in a temporary base dir
+    ///
+    /// The base dir is removed again once the fixture is dropped.
+    struct Fixture {
+        base_dir: PathBuf,
+        machines_dir: PathBuf,
+        generations: Generations,
+        keep: u32,
+    }
+
+    impl Fixture {
+        fn new(name: &str, keep: u32) -> Self {
+            let base_dir =
+                std::env::temp_dir().join(format!("forrest-test-{name}-{}", std::process::id()));
+
+            let _ = remove_dir_all(&base_dir);
+            create_dir_all(base_dir.join("run")).unwrap();
+
+            let triplet = Triplet::new("owner", "repo", "build");
+
+            Self {
+                machines_dir: base_dir.join("machines/owner/repo"),
+                generations: Generations::new(&triplet, &base_dir),
+                base_dir,
+                keep,
+            }
+        }
+
+        /// Persist a disk image with the given content as a new generation
+        fn persist(&self, content: &str) -> u32 {
+            self.persist_image(content, None, false)
+        }
+
+        /// Persist a disk image with the given content as a candidate for the next generation
+        fn persist_candidate(&self, content: &str) -> u32 {
+            self.persist_image(content, None, true)
+        }
+
+        /// Persist an image of a machine that started from `base_generation`
+        fn persist_based_on(&self, base_generation: Option<u32>) -> u32 {
+            self.persist_image("image", base_generation, false)
+        }
+
+        fn persist_image(
+            &self,
+            content: &str,
+            base_generation: Option<u32>,
+            candidate: bool,
+        ) -> u32 {
+            let disk = self.base_dir.join("run/disk.img");
+            write(&disk, content).unwrap();
+
+            let mut provenance: Provenance = serde_json::from_str(PROVENANCE).unwrap();
+            provenance.base_generation = base_generation;
+
+            self.generations
+                .persist(&[("img", &disk)], self.keep, &mut provenance, candidate)
+                .unwrap()
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = remove_dir_all(&self.base_dir);
+        }
+    }
+
+    #[test]
+    fn persist_and_prune() {
+        let fixture = Fixture::new("generations", 2);
+        let generations = &fixture.generations;
+        let machines_dir = &fixture.machines_dir;
+
+        // An image persisted by an older version of Forrest
+        create_dir_all(machines_dir).unwrap();
+        write(machines_dir.join("build.img"), "legacy").unwrap();
+
+        for content in ["first", "second", "third"] {
+            fixture.persist(content);
+        }
+
+        assert_eq!(generations.list().unwrap(), [2, 3]);
+
+        let provenance = Provenance::read(&machines_dir.join("build.json"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(provenance.generation, Some(3));
+        assert_eq!(generations.current().unwrap(), Some(3));
+
+        let image = generations.image().unwrap();
+        assert_eq!(image, machines_dir.join("build.3.img"));
+        assert_eq!(
+            read_to_string(machines_dir.join("build.img")).unwrap(),
+            "third"
+        );
+
+        generations.point_to(2).unwrap();
+
+        assert_eq!(generations.current().unwrap(), Some(2));
+        assert_eq!(
+            read_to_string(machines_dir.join("build.img")).unwrap(),
+            "second"
+        );
+    }
+
+    #[test]
+    fn rollback_without_state() {
+        let fixture = Fixture::new("rollback-without-state", 3);
+        let generations = &fixture.generations;
+        let machines_dir = &fixture.machines_dir;
+
+        fixture.persist("first");
+
+        // A later run also persisted its UEFI variables and TPM state
+        let run_dir = fixture.base_dir.join("run");
+        let (disk, efivars, swtpm) = (
+            run_dir.join("disk.img"),
+            run_dir.join("efivars.fd"),
+            run_dir.join("tpm.swtpm"),
+        );
+
+        for path in [&disk, &efivars, &swtpm] {
+            write(path, "second").unwrap();
+        }
+
+        let mut provenance: Provenance = serde_json::from_str(PROVENANCE).unwrap();
+        let files = [("img", &*disk), ("efivars", &*efivars), ("swtpm", &*swtpm)];
+        generations
+            .persist(&files, 3, &mut provenance, false)
+            .unwrap();
+
+        assert_eq!(
+            generations.efivars().unwrap(),
+            machines_dir.join("build.1.efivars")
+        );
+        assert_eq!(
+            generations.tpm_state().unwrap(),
+            machines_dir.join("build.1.swtpm")
+        );
+
+        // Rolling back must not combine the old image with the newer state
+        generations.point_to(0).unwrap();
+
+        assert_eq!(generations.current().unwrap(), Some(0));
+        assert!(!machines_dir.join("build.efivars").is_symlink());
+        assert!(!machines_dir.join("build.swtpm").is_symlink());
+        assert!(!generations.tpm_state().unwrap().exists());
+
+        // A TPM state set up using `tpm init` is not a pointer and is kept
+        write(machines_dir.join("build.swtpm"), "tpm init").unwrap();
+        generations.point_to(0).unwrap();
+        assert_eq!(
+            read_to_string(generations.tpm_state().unwrap()).unwrap(),
+            "tpm init"
+        );
+    }
+
+    #[test]
+    fn quarantine() {
+        let fixture = Fixture::new("quarantine", 3);
+        let generations = &fixture.generations;
+        let machines_dir = &fixture.machines_dir;
+
+        fixture.persist("first");
+        fixture.persist("second");
+
+        // Fall back to the previous generation
+        assert_eq!(generations.quarantine(1, 3).unwrap(), Some(0));
+        assert_eq!(generations.list().unwrap(), [0]);
+        assert_eq!(generations.quarantined().unwrap().len(), 1);
+        assert_eq!(
+            read_to_string(machines_dir.join("build.img")).unwrap(),
+            "first"
+        );
+
+        // The number of the quarantined generation is not re-used
+        assert_eq!(fixture.persist("third"), 2);
+
+        // Fall back to the base image if there is no generation left
+        generations.quarantine(2, 3).unwrap();
+        assert_eq!(generations.quarantine(0, 3).unwrap(), None);
+        assert!(!machines_dir.join("build.img").is_symlink());
+        assert!(!machines_dir.join("build.json").is_symlink());
+        assert!(generations.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn promotion() {
+        let fixture = Fixture::new("promotion", 3);
+        let generations = &fixture.generations;
+
+        fixture.persist("first");
+
+        // A candidate does not become the current generation by itself
+        assert_eq!(fixture.persist_candidate("second"), 1);
+        assert_eq!(generations.current().unwrap(), Some(0));
+        assert_eq!(generations.candidate().unwrap(), Some(1));
+
+        assert!(generations.promote(1).unwrap());
+        assert_eq!(generations.current().unwrap(), Some(1));
+        assert_eq!(generations.candidate().unwrap(), None);
+
+        // A discarded candidate is removed
+        assert_eq!(fixture.persist_candidate("third"), 2);
+        generations.discard(2).unwrap();
+        assert_eq!(generations.candidate().unwrap(), None);
+        assert_eq!(generations.list().unwrap(), [0, 1]);
+        assert_eq!(generations.current().unwrap(), Some(1));
+    }
+
+    #[test]
+    fn persist_conflict() {
+        let fixture = Fixture::new("persist-conflict", 3);
+        let generations = &fixture.generations;
+
+        let conflict = |policy, newest_at_start, base| {
+            generations
+                .persist_conflict(policy, newest_at_start, base)
+                .unwrap()
+                .is_some()
+        };
+
+        // Nothing was persisted while the machine ran
+        fixture.persist_based_on(None);
+        assert!(!conflict(PersistConflictPolicy::First, Some(0), Some(0)));
+
+        // Two machines started from generation 0 and one of them persisted generation 1
+        fixture.persist_based_on(Some(0));
+        assert!(conflict(PersistConflictPolicy::First, Some(0), Some(0)));
+        assert!(!conflict(PersistConflictPolicy::Last, Some(0), Some(0)));
+        assert!(!conflict(
+            PersistConflictPolicy::NewestBase,
+            Some(0),
+            Some(0)
+        ));
+
+        // A machine that started from the base image loses against generation 1,
+        // which is based on generation 0
+        assert!(conflict(PersistConflictPolicy::NewestBase, Some(0), None));
+
+        // The newest generation was quarantined while the machine ran
+        generations.quarantine(1, 3).unwrap();
+        assert!(!conflict(PersistConflictPolicy::First, Some(1), Some(1)));
+
+        // A candidate was discarded while the machine ran
+        assert_eq!(fixture.persist_candidate("candidate"), 2);
+        generations.discard(2).unwrap();
+        assert!(!conflict(PersistConflictPolicy::First, Some(2), Some(0)));
+    }
+}
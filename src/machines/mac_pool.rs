@@ -1,30 +1,187 @@
+use std::collections::BTreeSet;
+use std::io::ErrorKind;
+use std::net::Ipv4Addr;
+use std::path::Path;
 use std::sync::Mutex;
 
+use log::info;
 use rand::RngExt;
 
-const MAC_MASK: u64 = 0xfe_ff_ff_ff_ff_ff;
-const LOCAL_BIT: u64 = 0x02_00_00_00_00_00;
+use crate::config::Addressing;
 
-static RETURNED: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+const LOCAL_BIT: u8 = 0x02;
+const MULTICAST_BIT: u8 = 0x01;
+
+// The prefix for interfaces that do not configure one is generated once
+// and stored in the base dir, so that it stays the same across restarts.
+const DEFAULT_PREFIX_FILE: &str = "mac_prefix";
+const DEFAULT_PREFIX_LEN: usize = 3;
+
+static IN_USE: Mutex<BTreeSet<u64>> = Mutex::new(BTreeSet::new());
+static DEFAULT_PREFIX: Mutex<Option<Vec<u8>>> = Mutex::new(None);
 
 pub struct Mac {
     addr: u64,
 }
 
-/// MAC Address pool with automatic reuse
+/// The addresses assigned to a network interface of a machine
+///
+/// The MAC address is returned to the pool once this is dropped.
+pub struct Lease {
+    mac: Mac,
+    ip: Option<(Ipv4Addr, u8)>,
+}
+
+fn parse_prefix(prefix: &str) -> std::io::Result<Vec<u8>> {
+    let invalid = || std::io::Error::other(format!("Invalid MAC address prefix '{prefix}'"));
+
+    let bytes: Vec<u8> = prefix
+        .split(':')
+        .map(|byte| u8::from_str_radix(byte, 16).map_err(|_| invalid()))
+        .collect::<std::io::Result<_>>()?;
+
+    // Leave at least one byte for the machines and only allow unicast addresses.
+    if bytes.is_empty() || bytes.len() > 5 || bytes[0] & MULTICAST_BIT != 0 {
+        return Err(invalid());
+    }
+
+    Ok(bytes)
+}
+
+fn parse_ip(ip: &str) -> std::io::Result<(Ipv4Addr, u8)> {
+    let invalid = || std::io::Error::other(format!("Invalid IP address '{ip}'"));
+
+    let (addr, prefix_len) = ip.split_once('/').ok_or_else(invalid)?;
+    let addr: Ipv4Addr = addr.parse().map_err(|_| invalid())?;
+    let prefix_len: u8 = prefix_len.parse().map_err(|_| invalid())?;
+
+    if prefix_len > 32 {
+        return Err(invalid());
+    }
+
+    Ok((addr, prefix_len))
+}
+
+/// Generate a random locally administered unicast MAC address prefix
+fn random_prefix() -> Vec<u8> {
+    let mut prefix: Vec<u8> = (0..DEFAULT_PREFIX_LEN)
+        .map(|_| rand::rng().random())
+        .collect();
+
+    prefix[0] = (prefix[0] | LOCAL_BIT) & !MULTICAST_BIT;
+
+    prefix
+}
+
+fn format_prefix(prefix: &[u8]) -> String {
+    let bytes: Vec<String> = prefix.iter().map(|b| format!("{b:02x}")).collect();
+
+    bytes.join(":")
+}
+
+/// Get the prefix for interfaces that do not configure one
 ///
-/// Forrest needs to assign MAC addresses to virtual machines that are unique
-/// at that point in time. We do hower want to reuse the MACs once they are no
-/// longer used, to make sure we do not run out of DHCP leases.
-pub fn get_mac() -> Mac {
-    let addr = RETURNED
-        .lock()
-        .unwrap()
-        .pop()
-        .unwrap_or_else(|| rand::rng().random());
-    let addr = (addr | LOCAL_BIT) & MAC_MASK;
-
-    Mac { addr }
+/// The prefix is read from the base dir or randomly generated and stored
+/// there if it does not exist yet.
+fn default_prefix(base_dir: &Path) -> std::io::Result<Vec<u8>> {
+    let mut default_prefix = DEFAULT_PREFIX.lock().unwrap();
+
+    if let Some(prefix) = default_prefix.as_ref() {
+        return Ok(prefix.clone());
+    }
+
+    let path = base_dir.join(DEFAULT_PREFIX_FILE);
+
+    let prefix = match std::fs::read_to_string(&path) {
+        Ok(content) => parse_prefix(content.trim())?,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let prefix = random_prefix();
+            let formatted = format_prefix(&prefix);
+
+            std::fs::write(&path, format!("{formatted}\n"))?;
+
+            info!("Generated MAC address prefix {formatted} for this host");
+
+            prefix
+        }
+        Err(e) => return Err(e),
+    };
+
+    *default_prefix = Some(prefix.clone());
+
+    Ok(prefix)
+}
+
+/// Assign addresses to a network interface
+///
+/// The MAC address is the lowest one in the configured range that is not
+/// currently in use by another machine.
+/// This means that the same MAC addresses are used again and again (also
+/// across restarts), to make sure we do not run out of DHCP leases.
+///
+/// If a static IP address is configured it is offset by the same amount
+/// as the MAC address, so that the two always belong together.
+pub fn lease(addressing: &Addressing, base_dir: &Path) -> std::io::Result<Lease> {
+    let prefix = match &addressing.mac_prefix {
+        Some(prefix) => parse_prefix(prefix)?,
+        None => default_prefix(base_dir)?,
+    };
+
+    let ip = addressing.ip.as_deref().map(parse_ip).transpose()?;
+
+    let host_bits = 8 * (6 - prefix.len() as u32);
+    let base = prefix
+        .iter()
+        .fold(0u64, |acc, b| (acc << 8) | u64::from(*b))
+        << host_bits;
+
+    let mut count = 1u64 << host_bits;
+
+    if let Some(mac_count) = addressing.mac_count {
+        count = count.min(u64::from(mac_count));
+    }
+
+    // Do not hand out IP addresses beyond the end of the subnet.
+    if let Some((addr, prefix_len)) = ip {
+        let host_mask = u32::MAX.checked_shr(u32::from(prefix_len)).unwrap_or(0);
+        let ips_left = u64::from(host_mask - (u32::from(addr) & host_mask)) + 1;
+
+        count = count.min(ips_left);
+    }
+
+    let mut in_use = IN_USE.lock().unwrap();
+
+    let index = (0..count)
+        .find(|index| !in_use.contains(&(base | index)))
+        .ok_or_else(|| {
+            let msg = format!(
+                "All {count} MAC addresses with prefix {} are in use",
+                format_prefix(&prefix)
+            );
+
+            std::io::Error::other(msg)
+        })?;
+
+    in_use.insert(base | index);
+
+    let mac = Mac { addr: base | index };
+    let ip =
+        ip.map(|(addr, prefix_len)| (Ipv4Addr::from(u32::from(addr) + index as u32), prefix_len));
+
+    Ok(Lease { mac, ip })
+}
+
+impl Lease {
+    /// The MAC address, like `52:54:00:00:00:01`
+    pub fn mac(&self) -> String {
+        self.mac.to_string()
+    }
+
+    /// The static IP address in CIDR notation, like `192.168.1.2/24`
+    pub fn ip(&self) -> Option<String> {
+        self.ip
+            .map(|(addr, prefix_len)| format!("{addr}/{prefix_len}"))
+    }
 }
 
 impl std::fmt::Display for Mac {
@@ -41,12 +198,14 @@ impl std::fmt::Display for Mac {
 
 impl Drop for Mac {
     fn drop(&mut self) {
-        RETURNED.lock().unwrap().push(self.addr);
+        IN_USE.lock().unwrap().remove(&self.addr);
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::path::Path;
+
     use super::*;
 
     #[test]
@@ -61,12 +220,51 @@ mod tests {
     #[test]
     fn test_local_and_unicast() {
         for _ in 0..1000 {
-            let mac_str = get_mac().to_string();
-            let nibble_two = mac_str.chars().nth(1).unwrap();
+            let prefix_str = format_prefix(&random_prefix());
+            let nibble_two = prefix_str.chars().nth(1).unwrap();
 
             // Mac sure the second nibble has the first bit unset (unicast) and
             // the second bit set (locally administered).
             assert!(['2', '6', 'a', 'e'].contains(&nibble_two));
         }
     }
+
+    #[test]
+    fn test_lowest_free() {
+        let addressing = Addressing {
+            mac_prefix: Some("52:54:00:aa:bb".into()),
+            mac_count: Some(2),
+            ip: Some("192.168.1.10/24".into()),
+        };
+
+        // The base dir is only used for the default prefix.
+        let base_dir = Path::new("/nonexistent");
+
+        let first = lease(&addressing, base_dir).unwrap();
+        let second = lease(&addressing, base_dir).unwrap();
+
+        assert_eq!(first.mac(), "52:54:00:aa:bb:00");
+        assert_eq!(first.ip().unwrap(), "192.168.1.10/24");
+        assert_eq!(second.mac(), "52:54:00:aa:bb:01");
+        assert_eq!(second.ip().unwrap(), "192.168.1.11/24");
+
+        // The range is exhausted
+        assert!(lease(&addressing, base_dir).is_err());
+
+        // Returned addresses are re-used
+        std::mem::drop(first);
+
+        let third = lease(&addressing, base_dir).unwrap();
+        assert_eq!(third.mac(), "52:54:00:aa:bb:00");
+        assert_eq!(third.ip().unwrap(), "192.168.1.10/24");
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(parse_prefix("53:54:00").is_err());
+        assert!(parse_prefix("52:54:00:00:00:00").is_err());
+        assert!(parse_prefix("52:xx").is_err());
+        assert!(parse_ip("192.168.1.1").is_err());
+        assert!(parse_ip("192.168.1.1/33").is_err());
+    }
 }
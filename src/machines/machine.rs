@@ -5,17 +5,37 @@ use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 use log::{debug, error, info, warn};
+use octocrab::models::workflows::Conclusion;
 use octocrab::models::RunnerGroupId;
-use octocrab::models::{actions::SelfHostedRunnerJitConfig, RunnerId};
+use octocrab::models::{actions::SelfHostedRunnerJitConfig, JobId, RunId, RunnerId};
 use rand::{distr::Alphanumeric, rng, RngExt};
+use serde::{Deserialize, Serialize};
+use tokio::net::UnixListener;
 use tokio::{process::Command, task::AbortHandle};
 
-use super::mac_pool::get_mac;
+use super::daemon::{self, Daemon};
+use super::egress::{self, Allowlist};
 use super::manager::{Machines, Rescheduler};
-use super::run_dir::RunDir;
+use super::network::Network;
+use super::provenance::{self, WorkflowRun};
+use super::run_dir::{PersistRequest, RunDir};
+use super::sparsify::sparsify;
+use super::ssh_access;
 use super::triplet::Triplet;
 use crate::auth::Auth;
-use crate::config::{ConfigFile, MachineConfig, NetworkInterface};
+use crate::config::{ConfigFile, MachineConfig, MachineProfile, PersistOn, ShareDriver};
+use crate::metrics::Metrics;
+
+// How often and in which interval to ask the API for the conclusion of a job
+// if it is not known when the machine stops.
+// The runner reports the conclusion before it exits, but it may take a moment
+// to become visible in the API.
+const CONCLUSION_ATTEMPTS: u32 = 3;
+const CONCLUSION_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+// The only label of runners that test a candidate image.
+// No job selects it, so that they never pick up a job.
+pub(super) const SMOKE_TEST_LABEL: &str = "forrest-smoke-test";
 
 // The arguments used to start the qemu process.
 //
@@ -28,21 +48,24 @@ const QEMU_ARGS: &[&[&str]] = &[
     &["-enable-kvm"],
     &["-nodefaults"],
     &["-nographic"],
-    &["-M", "type=q35,accel=kvm,smm=on"],
     &["-cpu", "max"],
-    &["-global", "ICH9-LPC.disable_s3=1"],
-    &["-device", "virtio-net-pci,netdev=uplink"],
-    &["-netdev", "user,id=uplink,ipv4=on,ipv6=on,ipv6-net=::/0"],
     &["-object", "rng-random,filename=/dev/urandom,id=rng0"],
-    &["-device", "virtio-rng-pci,rng=rng0,id=rng-device0"],
-    &["-device", "VGA,vgamem_mb=4"],
-    &["-device", "isa-serial,chardev=bootlog"],
-    &["-device", "isa-serial,chardev=telnet"],
     &["-chardev", "file,id=bootlog,path=log.txt"],
     &[
         "-chardev",
         "socket,id=telnet,server=on,wait=off,path=shell.sock",
     ],
+];
+
+// The arguments specific to the default q35 machine profile,
+// which emulates a full PC with firmware boot and legacy devices.
+const QEMU_ARGS_Q35: &[&[&str]] = &[
+    &["-M", "type=q35,accel=kvm,smm=on"],
+    &["-global", "ICH9-LPC.disable_s3=1"],
+    &["-device", "virtio-rng-pci,rng=rng0,id=rng-device0"],
+    &["-device", "VGA,vgamem_mb=4"],
+    &["-device", "isa-serial,chardev=bootlog"],
+    &["-device", "isa-serial,chardev=telnet"],
     &[
         "-drive",
         "if=virtio,format=raw,discard=unmap,cache.writeback=on,cache.direct=on,cache.no-flush=on,file=disk.img",
@@ -56,18 +79,54 @@ const QEMU_ARGS: &[&[&str]] = &[
         "if=virtio,format=raw,discard=unmap,cache.writeback=on,cache.direct=on,cache.no-flush=on,file=job-config.img",
     ],
 ];
+
+// The arguments specific to the microvm machine profile.
+// It has no PCI bus, VGA, ICH9 or second ISA serial port and can only boot a
+// kernel directly.
+// The boot log is written by the single ISA serial port (ttyS0 in the guest),
+// while the shell is provided via a virtio console (hvc0 in the guest).
+const QEMU_ARGS_MICROVM: &[&[&str]] = &[
+    &[
+        "-M",
+        "type=microvm,accel=kvm,pit=off,pic=off,rtc=off,isa-serial=on",
+    ],
+    &["-device", "virtio-rng-device,rng=rng0,id=rng-device0"],
+    &["-serial", "chardev:bootlog"],
+    &["-device", "virtio-serial-device"],
+    &["-device", "virtconsole,chardev=telnet"],
+    &[
+        "-drive",
+        "id=disk,if=none,format=raw,discard=unmap,cache.writeback=on,cache.direct=on,cache.no-flush=on,file=disk.img",
+    ],
+    &["-device", "virtio-blk-device,drive=disk"],
+    &[
+        "-drive",
+        "id=cloud-init,if=none,format=raw,discard=unmap,cache.writeback=on,cache.direct=on,cache.no-flush=on,file=cloud-init.img",
+    ],
+    &["-device", "virtio-blk-device,drive=cloud-init"],
+    &[
+        "-drive",
+        "id=job-config,if=none,format=raw,discard=unmap,cache.writeback=on,cache.direct=on,cache.no-flush=on,file=job-config.img",
+    ],
+    &["-device", "virtio-blk-device,drive=job-config"],
+];
+
 const QEMU_ARGS_SWTPM: &[&[&str]] = &[
     &["-chardev", "socket,id=chrtpm,path=swtpm.ctrl"],
     &["-tpmdev", "emulator,id=tpm0,chardev=chrtpm"],
     &["-device", "tpm-tis,tpmdev=tpm0"],
 ];
 
-const SWTPM_CMD: &str = "/usr/bin/swtpm";
+const VIRTIOFSD_CMD: &str = "/usr/libexec/virtiofsd";
+const VIRTIOFSD_ARGS: &[&str] = &["--sandbox=none", "--cache=auto"];
+
+pub(super) const SWTPM_CMD: &str = "/usr/bin/swtpm";
 const SWTPM_ARGS: &[&[&str]] = &[
     &["socket"],
     &["--tpm2"],
     &["--tpmstate", "backend-uri=file://tpm.swtpm"],
     &["--ctrl", "type=unixio,path=swtpm.ctrl"],
+    &["--log", "fd=2"],
 ];
 
 #[derive(PartialEq, Clone, Copy, Debug)]
@@ -79,6 +138,7 @@ pub(super) enum Status {
     Waiting,
     Running,
     Stopping,
+    Held,
     Stopped,
 }
 
@@ -90,19 +150,42 @@ struct Inner {
     run_dir: Option<RunDir>,
     started: Option<Instant>,
     status: Status,
+    status_since: Instant,
     artifact_quota_remaining: Vec<u64>,
+    run_id: Option<RunId>,
+    job_id: Option<JobId>,
+    workflow_run: Option<WorkflowRun>,
+    conclusion: Option<Conclusion>,
+    hold_requested: bool,
+    powered_off: bool,
 }
 
 pub struct Machine {
     auth: Arc<Auth>,
     cfg: Arc<ConfigFile>,
     inner: Mutex<Inner>,
+    metrics: Metrics,
     rescheduler: Rescheduler,
     runner_name: String,
     run_token: String,
+    smoke_test: bool,
     triplet: Triplet,
 }
 
+/// A snapshot of the state of a machine, e.g. for the status API
+#[derive(Serialize, Deserialize)]
+pub struct MachineInfo {
+    pub triplet: String,
+    pub runner_name: String,
+    pub status: String,
+    /// How long the machine has been in its current status
+    pub status_secs: u64,
+    /// The RAM (in bytes) the machine needs once it runs
+    pub ram_required: u64,
+    /// The RAM (in bytes) the machine currently takes up
+    pub ram_consumed: u64,
+}
+
 pub struct Artifact<'a> {
     config: &'a crate::config::Artifact,
     machine: &'a Machine,
@@ -120,7 +203,7 @@ impl Status {
             | Self::Registered
             | Self::Starting
             | Self::Waiting => true,
-            Self::Running | Self::Stopping | Self::Stopped => false,
+            Self::Running | Self::Stopping | Self::Held | Self::Stopped => false,
         }
     }
 
@@ -144,12 +227,20 @@ impl std::fmt::Display for Status {
             Self::Waiting => "waiting",
             Self::Running => "running",
             Self::Stopping => "stopping",
+            Self::Held => "held",
             Self::Stopped => "stopped",
         })
     }
 }
 
 impl Inner {
+    fn set_status(&mut self, status: Status) {
+        if self.status != status {
+            self.status = status;
+            self.status_since = Instant::now();
+        }
+    }
+
     /// The configuration string to pass to the action runner executable.
     fn encoded_jit_config(&self) -> Option<String> {
         self.jit_config
@@ -171,15 +262,20 @@ impl Machine {
     ///   its lifetime.
     /// * `auth` - The authentication cache we use to register the jit runner with
     ///   GitHub. This has to know about the user in `triplet` already.
+    /// * `metrics` - Where to count e.g. persisted images and uploaded artifacts.
     /// * `rescheduler` - Used to trigger a reschedule from the `machines::Manager`
     ///   once the machine exits and its resources are available to other machines.
     /// * `triplet` - The (owner, repository, machine name) triplet that requested
     ///   this machine.
+    /// * `smoke_test` - Start the machine from the candidate image of its type
+    ///   to test it instead of running a job on it.
     pub(super) fn new(
         cfg: Arc<ConfigFile>,
         auth: Arc<Auth>,
+        metrics: Metrics,
         rescheduler: Rescheduler,
         triplet: Triplet,
+        smoke_test: bool,
     ) -> Option<Arc<Self>> {
         let machine_config = cfg
             .repositories
@@ -221,11 +317,18 @@ impl Machine {
 
         let inner = Mutex::new(Inner {
             status: Status::Requested,
+            status_since: Instant::now(),
             run_dir: None,
             abort: None,
             jit_config: None,
             started: None,
             artifact_quota_remaining,
+            run_id: None,
+            job_id: None,
+            workflow_run: None,
+            conclusion: None,
+            hold_requested: false,
+            powered_off: false,
         });
 
         Some(Arc::new(Self {
@@ -233,9 +336,11 @@ impl Machine {
             rescheduler,
             runner_name,
             run_token,
+            smoke_test,
             auth,
             cfg,
             inner,
+            metrics,
         }))
     }
 
@@ -256,7 +361,7 @@ impl Machine {
             Status::Registered => 2,
             Status::Starting => 3,
             Status::Waiting => 4,
-            Status::Running | Status::Stopping | Status::Stopped => u32::MAX,
+            Status::Running | Status::Stopping | Status::Held | Status::Stopped => u32::MAX,
         }
     }
 
@@ -268,7 +373,7 @@ impl Machine {
         &self.triplet
     }
 
-    pub(super) fn run_token(&self) -> &str {
+    pub fn run_token(&self) -> &str {
         &self.run_token
     }
 
@@ -311,11 +416,18 @@ impl Machine {
 
     /// The amount of RAM (in bytes) the machine may currently consume
     pub(super) fn ram_consumed(&self) -> u64 {
-        match self.inner().status {
+        let inner = self.inner();
+
+        match inner.status {
             Status::Requested | Status::Registering | Status::Registered | Status::Stopped => 0,
             Status::Starting | Status::Waiting | Status::Running | Status::Stopping => {
                 self.ram_required()
             }
+            // Only the disk of a held machine that powered itself off is kept.
+            Status::Held => match inner.powered_off {
+                true => 0,
+                false => self.ram_required(),
+            },
         }
     }
 
@@ -341,6 +453,50 @@ impl Machine {
         }
     }
 
+    /// The generation of the machine image this machine started from (if any)
+    pub(super) fn image_generation(&self) -> Option<u32> {
+        self.inner()
+            .run_dir
+            .as_ref()
+            .and_then(|run_dir| run_dir.generation())
+    }
+
+    /// Was this machine started to test a candidate image instead of running a job?
+    pub(super) fn is_smoke_test(&self) -> bool {
+        self.smoke_test
+    }
+
+    /// The candidate generation this machine was started from to test it (if any)
+    pub(super) fn smoke_test_generation(&self) -> Option<u32> {
+        self.inner()
+            .run_dir
+            .as_ref()
+            .and_then(|run_dir| run_dir.smoke_test_generation())
+    }
+
+    /// Discard the candidate image this machine tests, e.g. because it did not start
+    pub(super) fn fail_smoke_test(&self, reason: &str) {
+        if let Some(run_dir) = self.inner().run_dir.as_mut() {
+            run_dir.fail_smoke_test(&self.cfg, reason);
+        }
+    }
+
+    pub(super) fn info(&self) -> MachineInfo {
+        let (status, status_since) = {
+            let inner = self.inner();
+            (inner.status, inner.status_since)
+        };
+
+        MachineInfo {
+            triplet: self.triplet.to_string(),
+            runner_name: self.runner_name.clone(),
+            status: status.to_string(),
+            status_secs: status_since.elapsed().as_secs(),
+            ram_required: self.ram_required(),
+            ram_consumed: self.ram_consumed(),
+        }
+    }
+
     pub(super) fn status(&self) -> Status {
         self.inner().status
     }
@@ -355,11 +511,16 @@ impl Machine {
             let triplet = machine.triplet();
             let installation_octocrab = machine.auth.user(machine.triplet.owner()).unwrap();
 
-            let labels = vec![
-                "self-hosted".to_owned(),
-                "forrest".to_owned(),
-                triplet.machine_name().into(),
-            ];
+            // Smoke test machines must not pick up jobs,
+            // so they do not get the labels jobs select their machines by.
+            let labels = match machine.smoke_test {
+                true => vec![SMOKE_TEST_LABEL.to_owned()],
+                false => vec![
+                    "self-hosted".to_owned(),
+                    "forrest".to_owned(),
+                    triplet.machine_name().into(),
+                ],
+            };
 
             let runner_group = RunnerGroupId(1);
 
@@ -375,6 +536,8 @@ impl Machine {
                 .send()
                 .await;
 
+            machine.metrics.github_api_call(&jit_config);
+
             let mut inner = machine.inner();
 
             match jit_config {
@@ -384,7 +547,7 @@ impl Machine {
                         machine.triplet, machine.runner_name, jc.runner.id
                     );
 
-                    inner.status = Status::Registered;
+                    inner.set_status(Status::Registered);
                     inner.jit_config = Some(jc);
                 }
                 Err(err) => {
@@ -393,7 +556,8 @@ impl Machine {
                         machine.triplet
                     );
 
-                    inner.status = Status::Stopped;
+                    inner.set_status(Status::Stopped);
+                    machine.rescheduler.failed_to_start(&machine.triplet);
                 }
             }
 
@@ -406,81 +570,228 @@ impl Machine {
             machine.rescheduler.reschedule();
         });
 
-        inner.status = Status::Registering;
+        inner.set_status(Status::Registering);
         inner.abort = Some(task.abort_handle());
     }
 
     /// Spawn the qemu process and wait for its completion
-    async fn qemu(&self) -> std::io::Result<()> {
+    async fn qemu(self: &Arc<Self>) -> std::io::Result<()> {
         let machine_config = self.machine_config();
+        let run_dir_path = self.inner().run_dir.as_ref().unwrap().path().to_path_buf();
 
-        // Set up virtfs directory forwarding from the host to the machine.
-        let virtfs_args = machine_config.shared.iter().flat_map(|dir| {
-            let mut arg = OsString::new();
+        // The machine profile decides which devices are emulated and how
+        // additional virtio devices are attached (via PCI or MMIO).
+        let (profile_args, virtio_suffix) = match machine_config.machine {
+            MachineProfile::Q35 => (QEMU_ARGS_Q35, "pci"),
+            MachineProfile::Microvm => (QEMU_ARGS_MICROVM, "device"),
+        };
 
-            let tag = &dir.tag;
-            let readonly = if dir.writable { "off" } else { "on" };
+        // Boot a kernel directly instead of going through the firmware and
+        // bootloader on the disk image.
+        let mut boot_args: Vec<OsString> = Vec::new();
 
-            write!(&mut arg, "local,security_model=none,",).unwrap();
-            write!(&mut arg, "mount_tag={tag},readonly={readonly},path=",).unwrap();
+        if let Some(kernel) = &machine_config.kernel {
+            boot_args.push("-kernel".into());
+            boot_args.push(kernel.into());
+        }
 
-            arg.push(dir.path.as_os_str());
+        if let Some(initrd) = &machine_config.initrd {
+            boot_args.push("-initrd".into());
+            boot_args.push(initrd.into());
+        }
 
-            ["-virtfs".into(), arg].into_iter()
-        });
+        if let Some(append) = &machine_config.append {
+            boot_args.push("-append".into());
+            boot_args.push(append.into());
+        }
 
-        // We need to keep a reference to the MAC addresses used until the
-        // VM exits, as they will be reused once they are dropped.
-        let mut macs = Vec::new();
-        let mut nic_args = Vec::new();
-
-        for (idx, ni) in machine_config.network_interfaces.iter().enumerate() {
-            match ni {
-                NetworkInterface::Vde(vde) => {
-                    let mac = get_mac();
-
-                    nic_args.push("-netdev".into());
-                    nic_args.push({
-                        let mut netdev_arg = OsString::new();
-                        write!(&mut netdev_arg, "vde,id=nic-{idx},sock=").unwrap();
-                        netdev_arg.push(vde.path.as_os_str());
-                        netdev_arg
-                    });
-                    nic_args.push("-device".into());
-                    nic_args.push({
-                        let mut device_arg = OsString::new();
-                        write!(&mut device_arg, "virtio-net-pci,netdev=nic-{idx},mac={mac}")
+        // Use UEFI firmware from pflash drives instead of the default BIOS.
+        // The variable store was copied to the run dir by `RunDir`.
+        if let Some(firmware) = &machine_config.firmware {
+            boot_args.push("-drive".into());
+            boot_args.push({
+                let mut drive_arg = OsString::from("if=pflash,format=raw,unit=0,readonly=on,file=");
+                drive_arg.push(firmware.code.as_os_str());
+                drive_arg
+            });
+            boot_args.push("-drive".into());
+            boot_args.push("if=pflash,format=raw,unit=1,file=efivars.fd".into());
+
+            if firmware.secure_boot {
+                // Only allow writes to the secure boot variables from SMM.
+                boot_args.push("-global".into());
+                boot_args.push("driver=cfi.pflash01,property=secure,value=on".into());
+            }
+        }
+
+        // Helper processes like virtiofsd that have to run alongside qemu.
+        // They are killed when they are dropped at the end of this function.
+        let mut daemons = Vec::new();
+
+        // Set up directory forwarding from the host to the machine,
+        // either using virtfs (9p) directly in qemu or using a virtiofsd per
+        // directory.
+        let mut share_args: Vec<OsString> = Vec::new();
+
+        for (idx, dir) in machine_config.shared.iter().enumerate() {
+            let tag = &dir.tag;
+
+            match dir.driver {
+                ShareDriver::Virtfs => {
+                    let readonly = if dir.writable { "off" } else { "on" };
+
+                    share_args.push("-fsdev".into());
+                    share_args.push({
+                        let mut fsdev_arg = OsString::new();
+                        write!(&mut fsdev_arg, "local,id=fsdev-{idx},security_model=none,")
                             .unwrap();
-                        device_arg
+                        write!(&mut fsdev_arg, "readonly={readonly},path=").unwrap();
+                        fsdev_arg.push(dir.path.as_os_str());
+                        fsdev_arg
                     });
+                    share_args.push("-device".into());
+                    share_args.push(
+                        format!("virtio-9p-{virtio_suffix},fsdev=fsdev-{idx},mount_tag={tag}")
+                            .into(),
+                    );
+                }
+                ShareDriver::Virtiofs => {
+                    let socket = format!("virtiofs-{idx}.sock");
+
+                    let mut virtiofsd = Command::new(VIRTIOFSD_CMD);
+
+                    virtiofsd
+                        .current_dir(&run_dir_path)
+                        .arg(format!("--socket-path={socket}"))
+                        .arg({
+                            let mut shared_dir_arg = OsString::from("--shared-dir=");
+                            shared_dir_arg.push(dir.path.as_os_str());
+                            shared_dir_arg
+                        })
+                        .args(VIRTIOFSD_ARGS);
+
+                    if !dir.writable {
+                        virtiofsd.arg("--readonly");
+                    }
+
+                    let daemon = Daemon::spawn(
+                        format!("virtiofsd for {tag} of {self}"),
+                        virtiofsd,
+                        run_dir_path.join(&socket),
+                        &run_dir_path.join(format!("virtiofsd-{idx}.log")),
+                    )
+                    .await?;
 
-                    macs.push(mac);
+                    daemons.push(daemon);
+
+                    share_args.push("-chardev".into());
+                    share_args.push(format!("socket,id=virtiofs-{idx},path={socket}").into());
+                    share_args.push("-device".into());
+                    share_args.push(
+                        format!("vhost-user-fs-{virtio_suffix},chardev=virtiofs-{idx},tag={tag}")
+                            .into(),
+                    );
                 }
             }
         }
 
-        // Spawn a software TPM emulation if a state file is present.
-        // We do not monitor the status of this process or wait for the control
-        // socket to be ready.
-        // Instead we just hope that it starts successfully.
-        let (_swtpm, swtpm_args) = {
+        // vhost-user devices like virtiofs need access to the guest RAM,
+        // which means it has to be shared with the daemon process.
+        let uses_vhost_user = machine_config
+            .shared
+            .iter()
+            .any(|dir| dir.driver == ShareDriver::Virtiofs);
+
+        if uses_vhost_user {
+            let ram = machine_config.ram.megabytes();
+
+            share_args.push("-object".into());
+            share_args.push(format!("memory-backend-memfd,id=mem,size={ram}M,share=on").into());
+            share_args.push("-machine".into());
+            share_args.push("memory-backend=mem".into());
+        }
+
+        // Set up the uplink and additional network interfaces.
+        // This has to be kept around until the VM exits, e.g. because
+        // the file descriptors of macvtap devices are closed once it is dropped.
+        let network = {
             let inner = self.inner();
-            let pwd = inner.run_dir.as_ref().unwrap();
+            let leases = inner.run_dir.as_ref().unwrap().leases();
 
-            if pwd.path().join("tpm.swtpm").exists() {
-                let mut swtpm = Command::new(SWTPM_CMD);
+            Network::new(machine_config, virtio_suffix, leases)?
+        };
+
+        for (guest, host) in network.port_forwards() {
+            info!("Forwarding 127.0.0.1:{host} to port {guest} of {self}");
+        }
+
+        ssh_access::write_port_forwards(&run_dir_path, network.port_forwards())?;
 
-                swtpm
-                    .kill_on_drop(true)
-                    .current_dir(pwd.path())
-                    .args(SWTPM_ARGS.iter().flat_map(|arg_list| *arg_list));
+        // Serve the egress proxy if the network access of the guest is restricted.
+        let egress_proxy = match &machine_config.egress {
+            Some(egress) if network.uses_egress_proxy() => {
+                let allowlist = Allowlist::new(egress)?;
+                let socket = run_dir_path.join("egress.sock");
 
-                let child = swtpm.spawn()?;
+                let _ = std::fs::remove_file(&socket);
+                let listener = UnixListener::bind(&socket)?;
 
-                (Some(child), QEMU_ARGS_SWTPM)
-            } else {
-                (None, [].as_slice())
+                Some(egress::serve(listener, allowlist, self.runner_name.clone()))
             }
+            Some(_) | None => None,
+        };
+
+        let egress_proxy = async move {
+            match egress_proxy {
+                Some(proxy) => proxy.await,
+                None => std::future::pending().await,
+            }
+        };
+
+        // Serve the API on a per-machine socket that is forwarded into the guest.
+        let guest_api = match network.forwards_api() {
+            true => {
+                let socket = run_dir_path.join("api.sock");
+
+                let _ = std::fs::remove_file(&socket);
+                let listener = UnixListener::bind(&socket)?;
+
+                Some(crate::api::serve_guest(listener, self.clone()))
+            }
+            false => None,
+        };
+
+        let guest_api = async move {
+            match guest_api {
+                Some(api) => api.await,
+                None => std::future::pending().await,
+            }
+        };
+
+        // Spawn a software TPM emulation if a state file is present
+        // and wait for it to be ready to accept connections from qemu.
+        // Like the other daemons it is monitored while qemu runs.
+        // qemu tells swtpm to exit while it shuts down, which is not an error.
+        let swtpm_args = if run_dir_path.join("tpm.swtpm").exists() {
+            let mut swtpm = Command::new(SWTPM_CMD);
+
+            swtpm
+                .current_dir(&run_dir_path)
+                .args(SWTPM_ARGS.iter().flat_map(|arg_list| *arg_list));
+
+            let daemon = Daemon::spawn(
+                format!("swtpm of {self}"),
+                swtpm,
+                run_dir_path.join("swtpm.ctrl"),
+                &run_dir_path.join("swtpm.log"),
+            )
+            .await?;
+
+            daemons.push(daemon);
+
+            QEMU_ARGS_SWTPM
+        } else {
+            [].as_slice()
         };
 
         // Assemble the complete set of arguments to pass to the qemu command.
@@ -499,15 +810,48 @@ impl Machine {
                 .arg("-smp")
                 .arg(&smp)
                 .args(QEMU_ARGS.iter().flat_map(|arg_list| *arg_list))
-                .args(virtfs_args)
-                .args(nic_args)
+                .args(profile_args.iter().flat_map(|arg_list| *arg_list))
+                .args(boot_args)
+                .args(share_args)
+                .args(network.args())
                 .args(swtpm_args.iter().flat_map(|arg_list| *arg_list));
 
+            // Pass file descriptors like those of macvtap devices on to qemu.
+            let inherited_fds = network.inherited_fds();
+
+            if !inherited_fds.is_empty() {
+                // SAFETY: The closure only calls fcntl(), which is async-signal-safe.
+                unsafe {
+                    qemu.pre_exec(move || {
+                        for fd in inherited_fds.iter() {
+                            if libc::fcntl(*fd, libc::F_SETFD, 0) == -1 {
+                                return Err(std::io::Error::last_os_error());
+                            }
+                        }
+
+                        Ok(())
+                    });
+                }
+            }
+
             qemu
         };
 
         // Actually run the qemu command and wait for its completion.
-        let status = qemu.status().await?;
+        // Stop the machine if any of the helper daemons exits prematurely.
+        let mut child = qemu.spawn()?;
+
+        let status = tokio::select! {
+            status = daemon::supervise(&mut child, &mut daemons) => status?,
+            res = egress_proxy => {
+                res?;
+                unreachable!("The egress proxy only returns on errors");
+            }
+            res = guest_api => {
+                res?;
+                unreachable!("The guest API only returns on errors");
+            }
+        };
 
         match status.success() {
             true => Ok(()),
@@ -529,12 +873,37 @@ impl Machine {
         let machine = self.clone();
 
         let task = tokio::spawn(async move {
-            match machine.qemu().await {
+            let res = machine.qemu().await;
+
+            machine
+                .inner()
+                .run_dir
+                .as_mut()
+                .unwrap()
+                .finish_smoke_test(&machine.cfg);
+
+            match res {
                 Ok(()) => {
                     info!("Machine {machine} has completed");
 
-                    let mut inner = machine.inner();
-                    inner.run_dir.as_mut().unwrap().maybe_persist();
+                    let persisted = machine.persist().await;
+
+                    match persisted {
+                        Ok(Some(image)) => {
+                            machine.metrics.persist(&machine.triplet, "success");
+
+                            if machine.machine_config().sparsify {
+                                if let Err(err) = sparsify(&image).await {
+                                    error!("Failed to sparsify {}: {err}", image.display());
+                                }
+                            }
+                        }
+                        Ok(None) => {}
+                        Err(err) => {
+                            error!("Failed to persist the disk image of {machine}: {err}");
+                            machine.metrics.persist(&machine.triplet, "failure");
+                        }
+                    }
                 }
                 Err(err) => error!("Failed to run machine {machine}: {err}",),
             }
@@ -543,6 +912,30 @@ impl Machine {
             // No need to abort this task anymore.
             machine.inner().abort = None;
 
+            // A held machine keeps its run dir (including the disk image) until
+            // the hold expires, even if the guest powered itself off.
+            // Its RAM can be used by other machines though.
+            let held = {
+                let mut inner = machine.inner();
+
+                inner.powered_off = inner.status == Status::Held;
+                inner.powered_off
+            };
+
+            if held {
+                info!(
+                    "Held machine {machine} powered off. Keeping its disk until the hold expires"
+                );
+                machine.rescheduler.reschedule();
+                return;
+            }
+
+            // Machines that stop before their runner came up failed to start.
+            // Smoke tests with a check script never start a runner.
+            if machine.status() == Status::Starting && !machine.smoke_test {
+                machine.rescheduler.failed_to_start(&machine.triplet);
+            }
+
             // Update our status to stopped and some other cleanup.
             machine.kill();
 
@@ -550,7 +943,7 @@ impl Machine {
             machine.rescheduler.reschedule();
         });
 
-        inner.status = Status::Starting;
+        inner.set_status(Status::Starting);
         inner.started = Some(Instant::now());
         inner.abort = Some(task.abort_handle());
     }
@@ -563,7 +956,7 @@ impl Machine {
             abort.abort()
         }
 
-        inner_locked.status = Status::Stopped;
+        inner_locked.set_status(Status::Stopped);
 
         if let Some(runner_id) = inner_locked.runner_id() {
             // We have to de-register the runner
@@ -582,6 +975,7 @@ impl Machine {
                     )
                     .await;
 
+                machine.metrics.github_api_call(&res);
                 machine.inner().jit_config = None;
 
                 match res {
@@ -630,7 +1024,8 @@ impl Machine {
                     Some(ejc) => ejc,
                     None => {
                         error!("Can not set up run dir for {self} due to missing jit config");
-                        inner.status = Status::Stopped;
+                        inner.set_status(Status::Stopped);
+                        self.rescheduler.failed_to_start(&self.triplet);
                         return;
                     }
                 };
@@ -641,7 +1036,8 @@ impl Machine {
                     Ok(run_dir) => inner.run_dir = run_dir,
                     Err(err) => {
                         error!("Failed to set up run dir for {self}: {err}");
-                        inner.status = Status::Stopped;
+                        inner.set_status(Status::Stopped);
+                        self.rescheduler.failed_to_start(&self.triplet);
                         return;
                     }
                 }
@@ -656,16 +1052,185 @@ impl Machine {
             | Status::Waiting
             | Status::Running
             | Status::Stopping
+            | Status::Held
             | Status::Stopped => {}
         }
     }
 
+    /// Record that the machine has taken up the job `job_id` of the workflow run `run_id`
+    ///
+    /// The details of the workflow run are fetched to record them in the
+    /// provenance of the disk image, if it is persisted.
+    /// If SSH access is enabled for this machine the user that triggered the run
+    /// is allowed to log into the machine using the SSH keys of their GitHub account.
+    pub(super) fn job_started(self: &Arc<Self>, run_id: RunId, job_id: JobId) {
+        {
+            let mut inner = self.inner();
+
+            if inner.run_id == Some(run_id) {
+                return;
+            }
+
+            inner.run_id = Some(run_id);
+            inner.job_id = Some(job_id);
+        }
+
+        let octocrab = match self.auth.user(self.triplet.owner()) {
+            Some(oc) => oc,
+            None => {
+                error!(
+                    "Could not authenticate as {} to get workflow run {run_id}",
+                    self.triplet.owner()
+                );
+                return;
+            }
+        };
+
+        let machine = self.clone();
+
+        tokio::spawn(async move {
+            let run = provenance::workflow_run(&octocrab, &machine.triplet, run_id).await;
+
+            machine.metrics.github_api_call(&run);
+
+            let run = match run {
+                Ok(run) => run,
+                Err(err) => {
+                    error!("Failed to get workflow run {run_id} of {machine}: {err}");
+                    return;
+                }
+            };
+
+            machine.inner().workflow_run = Some(run.clone());
+
+            if !machine.machine_config().ssh_access {
+                return;
+            }
+
+            let (login, keys) = match ssh_access::run_actor_keys(&octocrab, &run).await {
+                Ok(Some(login_and_keys)) => login_and_keys,
+                Ok(None) => {
+                    info!("Workflow run {run_id} has no actor to grant SSH access to {machine}");
+                    return;
+                }
+                Err(err) => {
+                    error!("Failed to get SSH keys to grant access to {machine}: {err}");
+                    return;
+                }
+            };
+
+            let inner = machine.inner();
+
+            let run_dir = match &inner.run_dir {
+                Some(run_dir) => run_dir,
+                None => return,
+            };
+
+            match ssh_access::write_keys(run_dir.path(), &login, &keys) {
+                Ok(()) => info!(
+                    "Granted SSH access to {machine} to GitHub user {login} ({} keys)",
+                    keys.len()
+                ),
+                Err(err) => error!("Failed to grant SSH access to {machine}: {err}"),
+            }
+        });
+    }
+
+    /// Record the conclusion of the job `job_id` of the workflow run `run_id`
+    pub(super) fn job_completed(&self, run_id: RunId, job_id: JobId, conclusion: Conclusion) {
+        let mut inner = self.inner();
+
+        // Short jobs may complete before we saw them in progress.
+        if inner.job_id.is_none() {
+            inner.run_id = Some(run_id);
+            inner.job_id = Some(job_id);
+        }
+
+        if inner.job_id == Some(job_id) {
+            debug!("Job {job_id} on {self} concluded with {conclusion:?}");
+            inner.conclusion = Some(conclusion);
+        }
+    }
+
+    /// Persist the disk image of the machine if the job requested it
+    ///
+    /// The conclusion of the job is only looked up if the job requested a
+    /// persist and the decision depends on it.
+    async fn persist(self: &Arc<Self>) -> std::io::Result<Option<PathBuf>> {
+        let request = self.inner().run_dir.as_mut().unwrap().persist_requested()?;
+
+        match request {
+            PersistRequest::Valid => {}
+            PersistRequest::None => return Ok(None),
+            PersistRequest::Rejected => {
+                self.metrics.persist(&self.triplet, "rejected");
+                return Ok(None);
+            }
+        }
+
+        let conclusion = match self.machine_config().persist_on {
+            PersistOn::Success => self.conclusion().await,
+            PersistOn::Always => self.inner().conclusion.clone(),
+        };
+
+        let mut inner = self.inner();
+        let inner = &mut *inner;
+
+        inner.run_dir.as_mut().unwrap().persist(
+            inner.run_id,
+            inner.job_id,
+            inner.workflow_run.as_ref(),
+            conclusion.as_ref(),
+        )
+    }
+
+    /// The conclusion of the job that ran on this machine
+    ///
+    /// The conclusion is requested from the API if it was not received via
+    /// webhook or polling yet.
+    async fn conclusion(self: &Arc<Self>) -> Option<Conclusion> {
+        let job_id = {
+            let inner = self.inner();
+
+            if inner.conclusion.is_some() {
+                return inner.conclusion.clone();
+            }
+
+            inner.job_id?
+        };
+
+        let octocrab = self.auth.user(self.triplet.owner())?;
+
+        for attempt in 1..=CONCLUSION_ATTEMPTS {
+            let job = provenance::job(&octocrab, &self.triplet, job_id).await;
+
+            self.metrics.github_api_call(&job);
+
+            match job {
+                Ok(job) if job.conclusion.is_some() => return job.conclusion,
+                Ok(_) => debug!("Job {job_id} on {self} has not concluded yet"),
+                Err(err) => warn!("Failed to get job {job_id} on {self}: {err}"),
+            }
+
+            if attempt < CONCLUSION_ATTEMPTS {
+                tokio::time::sleep(CONCLUSION_RETRY_INTERVAL).await;
+            }
+
+            // The conclusion may have arrived via webhook in the meantime.
+            if let Some(conclusion) = self.inner().conclusion.clone() {
+                return Some(conclusion);
+            }
+        }
+
+        None
+    }
+
     /// Update the state of the machine using feedback from jobs and runner API
     ///
     /// The feedback we get from job states may be able to tell us if the machine
     /// is online (because it could not be processing a job otherwise) but it
     /// can not tell us if the machine is offline, hence the `Option<bool>`.
-    pub(super) fn status_feedback(&self, online: Option<bool>, busy: bool) {
+    pub(super) fn status_feedback(self: &Arc<Self>, online: Option<bool>, busy: bool) {
         let mut inner = self.inner();
 
         let new = match (&inner.status, online, busy) {
@@ -677,6 +1242,7 @@ impl Machine {
             (Status::Waiting, Some(true) | None, false) => Status::Waiting,
             (Status::Running, Some(true) | None, true) => Status::Running,
             (Status::Stopping, _, _) => Status::Stopping,
+            (Status::Held, _, _) => Status::Held,
             (Status::Stopped, _, _) => Status::Stopped,
 
             // The action runner on the machine has registered itself
@@ -691,18 +1257,154 @@ impl Machine {
             | (Status::Running, Some(false), _)
             | (Status::Running, _, false) => {
                 inner.jit_config = None;
-
                 Status::Stopping
             }
         };
 
+        // A machine that was started to test a candidate image
+        // has passed the test once it registered as runner.
+        if inner.status == Status::Starting && matches!(new, Status::Waiting | Status::Running) {
+            if let Some(run_dir) = inner.run_dir.as_mut() {
+                run_dir.registered();
+            }
+        }
+
         if inner.status != new {
             info!(
                 "Machine {self} transitioned from state {} to {new}",
                 inner.status
             );
-            inner.status = new;
+            inner.set_status(new);
+        }
+
+        // A smoke test machine is done once its runner registered.
+        if self.smoke_test && matches!(new, Status::Waiting | Status::Running) {
+            std::mem::drop(inner);
+
+            info!("Stopping {self} after its smoke test");
+            self.kill();
+        }
+    }
+
+    /// How long machines of this type may be held after their job failed
+    fn hold_duration(&self) -> Result<Duration, String> {
+        match &self.machine_config().hold_on_failure {
+            Some(hold_on_failure) => Ok(hold_on_failure.duration),
+            None => Err("Holding is not enabled for this machine type".into()),
+        }
+    }
+
+    /// Request to keep the machine running if its job fails, e.g. to debug it
+    ///
+    /// This is requested by the guest via the API while the job runs.
+    /// The hold is only granted once the job has failed (see `grant_hold()`).
+    /// Returns how long the machine will be held or why it can not be.
+    pub fn request_hold(&self) -> Result<Duration, String> {
+        let duration = self.hold_duration()?;
+
+        let mut inner = self.inner();
+
+        match inner.status {
+            Status::Waiting | Status::Running | Status::Stopping | Status::Held => {}
+            _ => return Err("The machine is not running a job".into()),
+        }
+
+        if !inner.hold_requested {
+            info!("Machine {self} will be held for {duration:?} if its job fails");
         }
+
+        inner.hold_requested = true;
+
+        Ok(duration)
+    }
+
+    /// Hold the machine if it requested it and its job failed
+    ///
+    /// This is called by the guest via the API once its job completed.
+    /// The conclusion of the job is looked up on the server side,
+    /// so that the guest can not hold machines of successful jobs.
+    /// Returns how long the machine is held or why it is not.
+    pub async fn grant_hold(self: &Arc<Self>) -> Result<Duration, String> {
+        let duration = self.hold_duration()?;
+
+        match (self.status(), self.inner().hold_requested) {
+            (Status::Held, _) => return Ok(duration),
+            (_, true) => {}
+            (_, false) => return Err("The machine did not request a hold".into()),
+        }
+
+        match self.conclusion().await {
+            Some(Conclusion::Failure | Conclusion::TimedOut) => {}
+            Some(conclusion) => return Err(format!("The job concluded with {conclusion:?}")),
+            None => return Err("The conclusion of the job is not known".into()),
+        }
+
+        let max_held_ram = self
+            .cfg
+            .repositories
+            .get(self.triplet.owner())
+            .and_then(|repos| repos.get(self.triplet.repository()))
+            .and_then(|repo| repo.max_held_ram.as_ref())
+            .map(|max_held_ram| max_held_ram.bytes());
+
+        self.rescheduler.hold(self, max_held_ram, duration)?;
+
+        Ok(duration)
+    }
+
+    /// Put the machine on hold and stop it once `duration` has passed
+    ///
+    /// Must be called with the list of machines locked by the `machines::Manager`,
+    /// which checks the `max_held_ram` quota of the repository.
+    pub(super) fn hold(self: &Arc<Self>, duration: Duration) -> Result<(), String> {
+        let mut inner = self.inner();
+
+        match inner.status {
+            Status::Waiting | Status::Running | Status::Stopping => {}
+            Status::Held => return Ok(()),
+            _ => return Err("The machine is not running anymore".into()),
+        }
+
+        self.start_hold(&inner, duration);
+
+        info!(
+            "Machine {self} transitioned from state {} to {}",
+            inner.status,
+            Status::Held
+        );
+        inner.set_status(Status::Held);
+
+        Ok(())
+    }
+
+    /// Announce how to access the held machine and stop it once the hold expires
+    fn start_hold(self: &Arc<Self>, inner: &Inner, duration: Duration) {
+        if let Some(run_dir) = &inner.run_dir {
+            info!(
+                "Holding {self} for {duration:?}. Its shell is available via {}",
+                run_dir.path().join("shell.sock").display()
+            );
+        }
+
+        if self.machine_config().ssh_access {
+            info!(
+                "Machine {self} is also available via `ssh -t <jump host> {}`",
+                self.runner_name
+            );
+        }
+
+        let machine = self.clone();
+
+        tokio::spawn(async move {
+            tokio::time::sleep(duration).await;
+
+            if machine.status() == Status::Held {
+                info!("The hold of machine {machine} has expired");
+
+                machine.kill();
+                machine.rescheduler.reschedule();
+            }
+        });
     }
 }
 
@@ -718,10 +1420,15 @@ impl<'a> Artifact<'a> {
 
         let remaining = &mut inner.artifact_quota_remaining[self.quota_index];
 
+        let metrics = &self.machine.metrics;
+        let triplet = &self.machine.triplet;
+
         if *remaining > bytes {
             *remaining -= bytes;
+            metrics.artifact_bytes(triplet, &self.config.name, bytes);
             true
         } else {
+            metrics.artifact_quota_rejection(triplet, &self.config.name);
             false
         }
     }
@@ -1,16 +1,24 @@
 use std::{
-    collections::HashMap,
-    io::ErrorKind,
-    path::Path,
+    collections::{HashMap, HashSet},
     sync::{Arc, Mutex},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+use chrono::{Local, Timelike};
 use log::{debug, error, info, warn};
-
-use super::machine::Machine;
+use octocrab::models::workflows::Conclusion;
+use octocrab::models::{JobId, RunId};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use super::generations::{self, Generations};
+use super::machine::{Machine, MachineInfo, Status, SMOKE_TEST_LABEL};
+use super::notify::{self, Event};
+use super::retention;
 use super::{OwnerAndRepo, Triplet};
-use crate::{auth::Auth, config::Config};
+use crate::auth::Auth;
+use crate::config::{Config, ConfigFile, Promotion};
+use crate::metrics::Metrics;
 
 // Machines should go from being booted to being registered with GitHub
 // in less than 15 minutes.
@@ -18,13 +26,63 @@ use crate::{auth::Auth, config::Config};
 // and unpack the runner binary first.
 const START_TIMEOUT: Duration = Duration::from_secs(15 * 60);
 
+// Machine types whose machines fail to start (e.g. because the jit runner
+// can not be registered or qemu does not start) do not get new machines
+// right away, so that a broken machine type does not loop on it.
+// The backoff doubles with each failed start in a row.
+const START_BACKOFF_MIN: Duration = Duration::from_secs(10);
+const START_BACKOFF_MAX: Duration = Duration::from_secs(15 * 60);
+
 pub type Machines = HashMap<Triplet, Vec<Arc<Machine>>>;
 
+/// The demand for and supply of machines of a type
+#[derive(Serialize, Deserialize)]
+pub struct Supply {
+    pub triplet: String,
+    /// The number of queued jobs
+    pub demand: u64,
+    /// The number of machines that are available to take up a job
+    pub available: usize,
+    /// The number of machines, including the ones that are busy
+    pub machines: usize,
+}
+
+/// A snapshot of the state of the machine manager, e.g. for the status API
+#[derive(Serialize, Deserialize)]
+pub struct ManagerStatus {
+    pub machines: Vec<MachineInfo>,
+    pub supply: Vec<Supply>,
+    pub ram_total: u64,
+    pub ram_used: u64,
+    pub ram_available: u64,
+    /// No new machines are started on the host
+    pub host_drained: bool,
+    /// The machine types no new machines are started for
+    pub drained_machines: Vec<String>,
+}
+
+/// The parts of the host operators asked to not start new machines on
+///
+/// Machines that already run a job are allowed to finish it,
+/// but available machines are killed.
+#[derive(Default)]
+struct Drain {
+    host: bool,
+    machines: HashSet<Triplet>,
+}
+
 #[derive(Clone)]
 pub struct Manager {
     auth: Arc<Auth>,
     config: Config,
+    demand: Arc<Mutex<HashMap<Triplet, u64>>>,
+    drain: Arc<Mutex<Drain>>,
     machines: Arc<Mutex<Machines>>,
+    metrics: Metrics,
+    start_failures: Arc<Mutex<HashMap<Triplet, u32>>>,
+    /// Failed starts in a row and when the last one happened per machine type
+    start_backoff: Arc<Mutex<HashMap<Triplet, (u32, Instant)>>>,
+    sweep_now: Arc<Notify>,
 }
 
 pub struct Rescheduler {
@@ -32,20 +90,32 @@ pub struct Rescheduler {
 }
 
 impl Manager {
-    pub fn new(config: Config, auth: Arc<Auth>) -> Self {
+    pub fn new(config: Config, auth: Arc<Auth>, metrics: Metrics) -> Self {
+        let demand = Arc::new(Mutex::new(HashMap::new()));
         let machines = Arc::new(Mutex::new(HashMap::new()));
+        let start_failures = Arc::new(Mutex::new(HashMap::new()));
+        let start_backoff = Arc::new(Mutex::new(HashMap::new()));
+        let drain = Arc::new(Mutex::new(Drain::default()));
+        let sweep_now = Arc::new(Notify::new());
 
         Self {
             auth,
             config,
+            demand,
+            drain,
             machines,
+            metrics,
+            start_failures,
+            start_backoff,
+            sweep_now,
         }
     }
 
     /// Get an object that can be used to trigger a re-schedule on this manager.
     ///
     /// This makes it easier to reason about other parts of the software that may
-    /// trigger a re-schedule but should not be able to do anything else on the Manager.
+    /// trigger a re-schedule but should not be able to do anything else on the Manager
+    /// (apart from some read-only queries).
     pub(super) fn rescheduler(&self) -> Rescheduler {
         Rescheduler {
             manager: self.clone(),
@@ -82,6 +152,98 @@ impl Manager {
             .cloned()
     }
 
+    pub fn machine_by_runner_name(&self, runner_name: &str) -> Option<Arc<Machine>> {
+        self.machines()
+            .values()
+            .flat_map(|machines_vec| machines_vec.iter())
+            .find(|machine| machine.runner_name() == runner_name)
+            .cloned()
+    }
+
+    /// Kill the machine `runner_name`, even if it is running a job
+    ///
+    /// Returns whether a machine with this name was found.
+    pub fn kill(&self, runner_name: &str) -> bool {
+        let machine = match self.machine_by_runner_name(runner_name) {
+            Some(machine) => machine,
+            None => return false,
+        };
+
+        info!("Killing {machine} on request");
+
+        machine.kill();
+
+        // Maybe schedule new machines in the space we freed.
+        self.reschedule();
+
+        true
+    }
+
+    /// Stop (or resume) starting new machines on the host or for a machine type
+    ///
+    /// Available machines of drained machine types are killed right away,
+    /// while machines that run a job are allowed to finish it.
+    pub fn drain(&self, triplet: Option<&Triplet>, drain: bool) {
+        {
+            let mut drained = self.drain.lock().unwrap();
+
+            match (triplet, drain) {
+                (None, drain) => drained.host = drain,
+                (Some(triplet), true) => {
+                    drained.machines.insert(triplet.clone());
+                }
+                (Some(triplet), false) => {
+                    drained.machines.remove(triplet);
+                }
+            }
+        }
+
+        self.apply_demand();
+    }
+
+    /// Make the janitor sweep right away instead of at its next interval
+    pub fn sweep_soon(&self) {
+        self.sweep_now.notify_one();
+    }
+
+    /// Quarantine a generation of a machine image (by default the current one)
+    ///
+    /// Returns the quarantined generation and the one new machines start from now.
+    pub fn quarantine_image(
+        &self,
+        triplet: &Triplet,
+        generation: Option<u32>,
+    ) -> std::io::Result<(u32, Option<u32>)> {
+        let cfg = self.config.get();
+
+        let (generation, fallback) = generations::quarantine(&cfg, triplet, generation)?;
+
+        let fallback_msg = match fallback {
+            Some(fallback) => format!("generation {fallback}"),
+            None => "the base image".to_owned(),
+        };
+
+        notify::emit(
+            &cfg,
+            Event {
+                name: "image_quarantined",
+                triplet,
+                message: format!(
+                    "Quarantined generation {generation} of {triplet} on request. New machines start from {fallback_msg}"
+                ),
+                details: vec![
+                    ("GENERATION", generation.to_string()),
+                    (
+                        "FALLBACK_GENERATION",
+                        fallback.map(|f| f.to_string()).unwrap_or_default(),
+                    ),
+                ],
+            },
+        );
+
+        Ok((generation, fallback))
+    }
+
     pub fn status_feedback(
         &self,
         triplet: &Triplet,
@@ -99,13 +261,100 @@ impl Manager {
 
         match machine {
             Some(machine) => {
+                let starting_duration = machine.starting_duration();
+
                 machine.status_feedback(online, busy);
+
+                // A machine that came up successfully means that the current
+                // image is fine, even if previous machines failed to start.
+                let came_up = matches!(machine.status(), Status::Waiting | Status::Running);
+
+                if let Some(boot_duration) = starting_duration.filter(|_| came_up) {
+                    self.start_failures.lock().unwrap().remove(triplet);
+                    self.start_backoff.lock().unwrap().remove(triplet);
+                    self.metrics.boot_duration(triplet, boot_duration);
+                }
+
                 true
             }
             None => false,
         }
     }
 
+    /// Tell the machine `runner_name` that it has taken up the job `job_id` of run `run_id`
+    pub fn job_started(&self, triplet: &Triplet, runner_name: &str, run_id: RunId, job_id: JobId) {
+        let machines = self.machines();
+
+        let machine = machines.get(triplet).and_then(|triplet_machines| {
+            triplet_machines
+                .iter()
+                .find(|machine| machine.runner_name() == runner_name)
+        });
+
+        if let Some(machine) = machine {
+            machine.job_started(run_id, job_id);
+        }
+    }
+
+    /// Tell the machine `runner_name` that the job `job_id` of run `run_id` has completed
+    pub fn job_completed(
+        &self,
+        triplet: &Triplet,
+        runner_name: &str,
+        run_id: RunId,
+        job_id: JobId,
+        conclusion: Conclusion,
+    ) {
+        let machines = self.machines();
+
+        let machine = machines.get(triplet).and_then(|triplet_machines| {
+            triplet_machines
+                .iter()
+                .find(|machine| machine.runner_name() == runner_name)
+        });
+
+        if let Some(machine) = machine {
+            machine.job_completed(run_id, job_id, conclusion);
+        }
+    }
+
+    /// Hold `machine` for `duration` if its repository has RAM left in its `max_held_ram` quota
+    ///
+    /// The list of machines stays locked from checking the quota until the
+    /// machine is held, so that concurrent holds can not exceed it together.
+    fn hold(
+        &self,
+        machine: &Arc<Machine>,
+        max_held_ram: Option<u64>,
+        duration: Duration,
+    ) -> Result<(), String> {
+        let machines = self.machines();
+
+        if let Some(max_held_ram) = max_held_ram {
+            let triplet = machine.triplet();
+
+            let held_ram: u64 = machines
+                .iter()
+                .filter(|(t, _)| {
+                    t.owner() == triplet.owner() && t.repository() == triplet.repository()
+                })
+                .flat_map(|(_, machines)| machines.iter())
+                .filter(|machine| machine.status() == Status::Held)
+                .map(|machine| machine.ram_consumed())
+                .sum();
+
+            if held_ram + machine.ram_required() > max_held_ram {
+                return Err("The repository has reached its quota of held RAM".into());
+            }
+        }
+
+        machine.hold(duration)
+    }
+
+    /// Update the number of queued jobs per machine type
+    ///
+    /// The demand is remembered, so that the idle machine pool can be
+    /// re-evaluated by the janitor without new input from the job manager.
     pub fn update_demand<'a>(&self, requested: impl Iterator<Item = &'a Triplet>) {
         let mut demand: HashMap<Triplet, u64> = HashMap::new();
 
@@ -121,6 +370,121 @@ impl Manager {
             debug!("  - {triplet}: {count}");
         }
 
+        *self.demand.lock().unwrap() = demand;
+
+        self.apply_demand();
+    }
+
+    /// The number of idle machines to keep per machine type right now
+    ///
+    /// Returns a mapping from triplet to `(min_idle, max_idle)` for all machine
+    /// types that want a pool of idle machines.
+    fn idle_limits(&self, cfg: &ConfigFile) -> HashMap<Triplet, (u64, u64)> {
+        let hour = Local::now().hour();
+        let mut limits = HashMap::new();
+
+        for (owner, repos) in cfg.repositories.iter() {
+            // Machines can only be registered as runners once we know how to
+            // authenticate as their owner, which we learn from the poller or
+            // incoming webhooks.
+            if self.auth.user(owner).is_none() {
+                continue;
+            }
+
+            for (repository, repo) in repos.iter() {
+                for (machine_name, machine_config) in repo.machines.iter() {
+                    let (min_idle, max_idle) = machine_config.idle_limits(hour);
+
+                    if max_idle == 0 {
+                        continue;
+                    }
+
+                    let triplet = Triplet::new(owner, repository, machine_name);
+
+                    limits.insert(triplet, (min_idle.into(), max_idle.into()));
+                }
+            }
+        }
+
+        limits
+    }
+
+    /// The machine types that have a candidate image waiting to be tested
+    fn untested_candidates(&self, cfg: &ConfigFile) -> Vec<Triplet> {
+        let mut triplets = Vec::new();
+
+        for (owner, repos) in cfg.repositories.iter() {
+            if self.auth.user(owner).is_none() {
+                continue;
+            }
+
+            for (repository, repo) in repos.iter() {
+                for (machine_name, machine_config) in repo.machines.iter() {
+                    if matches!(machine_config.promotion, Promotion::None) {
+                        continue;
+                    }
+
+                    let triplet = Triplet::new(owner, repository, machine_name);
+
+                    match Generations::new(&triplet, &cfg.host.base_dir).candidate() {
+                        Ok(Some(_)) => triplets.push(triplet),
+                        Ok(None) => {}
+                        Err(e) => error!("Failed to look up the candidate image of {triplet}: {e}"),
+                    }
+                }
+            }
+        }
+
+        triplets
+    }
+
+    /// The machine types that do not get new machines right now,
+    /// because their machines recently failed to start
+    fn backing_off(&self) -> HashSet<Triplet> {
+        self.start_backoff
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, (failures, last))| {
+                let backoff = START_BACKOFF_MIN
+                    .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+                    .min(START_BACKOFF_MAX);
+
+                last.elapsed() < backoff
+            })
+            .map(|(triplet, _)| triplet.clone())
+            .collect()
+    }
+
+    /// Create and kill machines to match the demand from queued jobs and idle pools
+    fn apply_demand(&self) {
+        let cfg = self.config.get();
+
+        let mut demand = self.demand.lock().unwrap().clone();
+        let mut idle_limits = self.idle_limits(&cfg);
+        let mut smoke_tests = self.untested_candidates(&cfg);
+        let backing_off = self.backing_off();
+
+        // Drained machine types neither get machines for queued jobs nor idle
+        // machines, which also kills the machines that are still available.
+        {
+            let drain = self.drain.lock().unwrap();
+
+            if drain.host {
+                demand.clear();
+                idle_limits.clear();
+                smoke_tests.clear();
+            }
+
+            demand.retain(|triplet, _| !drain.machines.contains(triplet));
+            idle_limits.retain(|triplet, _| !drain.machines.contains(triplet));
+            smoke_tests.retain(|triplet| !drain.machines.contains(triplet));
+        }
+
+        // Machines that are available but not accounted for by queued jobs.
+        // These may be killed again if the RAM is required for queued jobs.
+        let mut idle_machines = Vec::new();
+
         let mut machines = self.machines();
 
         for (triplet, triplet_machines) in machines.iter_mut() {
@@ -137,34 +501,127 @@ impl Manager {
             for machine in triplet_machines.iter().rev() {
                 // Machines that are already servicing jobs do not count into the
                 // supply/demand calculation.
-                if !machine.status().is_available() {
+                // Neither do machines that test a candidate image, as they
+                // never take up a job.
+                if !machine.status().is_available() || machine.is_smoke_test() {
                     continue;
                 }
 
                 // Reduce the demand for this machine type by one.
-                // If the demand is already zero, then kill the machine.
+                // If the demand is already zero, then check if we may keep
+                // the machine around as idle machine or kill it otherwise.
                 match demand.get_mut(triplet) {
-                    Some(0) | None => machine.kill(),
+                    Some(0) | None => match idle_limits.get_mut(triplet) {
+                        Some((min_idle, max_idle)) if *max_idle > 0 => {
+                            *min_idle = min_idle.saturating_sub(1);
+                            *max_idle -= 1;
+
+                            idle_machines.push(machine.clone());
+                        }
+                        _ => machine.kill(),
+                    },
                     Some(count) => *count -= 1,
                 }
             }
         }
 
         // Add machines where the demand surpasses the supply
-        let cfg = self.config.get();
-
         for (triplet, count) in demand {
+            if count > 0 && backing_off.contains(&triplet) {
+                debug!("Not adding machines for {triplet}, because its machines failed to start");
+                continue;
+            }
+
             for _ in 0..count {
                 let cfg = cfg.clone();
                 let auth = self.auth.clone();
+                let metrics = self.metrics.clone();
                 let rescheduler = self.rescheduler();
 
-                if let Some(m) = Machine::new(cfg, auth, rescheduler, triplet.clone()) {
+                let m = Machine::new(cfg, auth, metrics, rescheduler, triplet.clone(), false);
+
+                if let Some(m) = m {
                     machines.entry(triplet.clone()).or_default().push(m);
                 }
             }
         }
 
+        // Test each candidate image using a dedicated machine,
+        // so that jobs never run on an untested image.
+        for triplet in smoke_tests {
+            let testing = machines
+                .get(&triplet)
+                .into_iter()
+                .flatten()
+                .any(|m| m.is_smoke_test() && !m.status().is_stopped());
+
+            if testing {
+                continue;
+            }
+
+            let cfg = cfg.clone();
+            let auth = self.auth.clone();
+            let metrics = self.metrics.clone();
+            let rescheduler = self.rescheduler();
+
+            if let Some(m) = Machine::new(cfg, auth, metrics, rescheduler, triplet.clone(), true) {
+                machines.entry(triplet).or_default().push(m);
+            }
+        }
+
+        // Idle machines may only use RAM that is not required for queued jobs.
+        // Kill idle machines until every machine fits into the RAM budget and
+        // only add new idle machines if there is still room for them.
+        let ram_total = cfg.host.ram.bytes();
+        let mut ram_committed: u64 = machines
+            .values()
+            .flat_map(|triplet_machines| triplet_machines.iter())
+            .filter(|m| !m.status().is_stopped())
+            .map(|m| m.ram_required())
+            .sum();
+
+        while ram_committed > ram_total {
+            let machine = match idle_machines.pop() {
+                Some(machine) => machine,
+                None => break,
+            };
+
+            debug!("Killing idle machine {machine} to make room for queued jobs");
+
+            ram_committed -= machine.ram_required();
+            machine.kill();
+        }
+
+        for (triplet, (min_idle, _)) in idle_limits {
+            if min_idle > 0 && backing_off.contains(&triplet) {
+                debug!(
+                    "Not adding idle machines for {triplet}, because its machines failed to start"
+                );
+                continue;
+            }
+
+            for _ in 0..min_idle {
+                let cfg = cfg.clone();
+                let auth = self.auth.clone();
+                let metrics = self.metrics.clone();
+                let rescheduler = self.rescheduler();
+
+                let m = match Machine::new(cfg, auth, metrics, rescheduler, triplet.clone(), false)
+                {
+                    Some(m) => m,
+                    None => break,
+                };
+
+                if ram_committed + m.ram_required() > ram_total {
+                    debug!("Not adding idle machine for {triplet} due to insufficient RAM");
+                    break;
+                }
+
+                ram_committed += m.ram_required();
+                machines.entry(triplet.clone()).or_default().push(m);
+            }
+        }
+
         // We must release the lock before calling reschedule
         std::mem::drop(machines);
         self.reschedule();
@@ -236,6 +693,8 @@ impl Manager {
                         .send()
                         .await;
 
+                    self.metrics.github_api_call(&runners_page);
+
                     let runners_page = match runners_page {
                         Ok(rp) => rp,
                         Err(e) => {
@@ -262,6 +721,16 @@ impl Manager {
 
                         let triplet = match oar.clone().into_triplet_via_labels(&labels) {
                             Some(triplet) => triplet,
+                            // Runners that test a candidate image lack the labels
+                            // jobs select machines by, so look them up by name.
+                            // Unknown ones get a triplet no machine has,
+                            // so that they are cleaned up below.
+                            None if labels == [SMOKE_TEST_LABEL] => {
+                                match self.machine_by_runner_name(&runner_name) {
+                                    Some(machine) => machine.triplet().clone(),
+                                    None => oar.clone().into_triplet(SMOKE_TEST_LABEL),
+                                }
+                            }
                             None => continue,
                         };
 
@@ -298,6 +767,8 @@ impl Manager {
                                 .delete_repo_runner(oar.owner(), oar.repository(), runner.id)
                                 .await;
 
+                            self.metrics.github_api_call(&res);
+
                             match res {
                                 Ok(()) => info!("De-registered orphaned runner {runner_name} on {oar}"),
                                 Err(err) => warn!("Failed to de-register orphaned runner {runner_name} from {oar}: {err}"),
@@ -311,62 +782,203 @@ impl Manager {
         // Go through each machine and check for timeouts
         let mut machines = self.machines();
 
-        let base_dir_path = Path::new(&cfg.host.base_dir);
-
         for (triplet, triplet_machines) in machines.iter_mut() {
             for machine in triplet_machines {
-                let runner_name = machine.runner_name();
-
                 let start_timeout_elapsed = machine
                     .starting_duration()
                     .map(|rt| rt > START_TIMEOUT)
                     .unwrap_or(false);
 
                 if start_timeout_elapsed {
-                    error!("Runner {runner_name} on {triplet} failed to come up in time");
-
-                    let machine_image_path = triplet.machine_image_path(base_dir_path);
+                    // Get the generation before killing the machine,
+                    // as its run dir goes away once it stopped.
+                    let generation = machine.image_generation();
 
                     machine.kill();
 
-                    let broken_image_path = {
-                        let mut filename = machine_image_path.file_name().unwrap().to_os_string();
-                        filename.push(".broken");
-                        machine_image_path.parent().unwrap().join(filename)
-                    };
+                    self.metrics.start_timeout(triplet);
+                    self.start_failed(&cfg, triplet, machine, generation);
+                }
+            }
+        }
+    }
 
-                    // Keep a copy of the broken image around for later investigation.
-                    // But move the original away so that later invocations run from seed
-                    // image again and hopefully succeed.
-                    let res = std::fs::rename(&machine_image_path, &broken_image_path);
+    /// Count a machine that failed to come up and quarantine its image if this happens too often
+    ///
+    /// The failures are counted per machine type and the count is reset
+    /// once a machine comes up successfully.
+    fn start_failed(
+        &self,
+        cfg: &ConfigFile,
+        triplet: &Triplet,
+        machine: &Machine,
+        generation: Option<u32>,
+    ) {
+        let quarantine = &machine.machine_config().quarantine;
+        let runner_name = machine.runner_name();
+
+        // A candidate image that can not be started from is discarded right away.
+        // It does not count towards the failures of the current image.
+        if machine.smoke_test_generation().is_some() {
+            machine.fail_smoke_test("The machine failed to come up in time");
+            return;
+        }
 
-                    let mip = machine_image_path.display();
-                    let bip = broken_image_path.display();
+        let failures = {
+            let mut start_failures = self.start_failures.lock().unwrap();
+            let failures = start_failures.entry(triplet.clone()).or_default();
 
-                    match res {
-                        Ok(()) => info!("Retained broken machine image as {bip}"),
-                        Err(e) if e.kind() == ErrorKind::NotFound => {
-                            info!(
-                                "Machine image {mip} not found. Machine likely started from seed."
-                            )
-                        }
-                        Err(e) => error!("Failed to remove broken disk image {bip}: {e}"),
-                    }
-                }
+            *failures += 1;
+            *failures
+        };
+
+        notify::emit(
+            cfg,
+            Event {
+                name: "start_failed",
+                triplet,
+                message: format!(
+                    "Runner {runner_name} on {triplet} failed to come up in time ({failures} times in a row)"
+                ),
+                details: vec![
+                    ("RUNNER_NAME", runner_name.to_owned()),
+                    ("FAILURES", failures.to_string()),
+                ],
+            },
+        );
+
+        if failures < quarantine.after_failures {
+            return;
+        }
+
+        let generation = match generation {
+            Some(generation) => generation,
+            None => {
+                info!("{runner_name} did not start from a persisted image. Nothing to quarantine");
+                return;
+            }
+        };
+
+        self.start_failures.lock().unwrap().remove(triplet);
+
+        let generations = Generations::new(triplet, &cfg.host.base_dir);
+
+        let fallback = match generations.quarantine(generation, quarantine.keep) {
+            Ok(fallback) => fallback,
+            Err(e) => {
+                error!("Failed to quarantine generation {generation} of {triplet}: {e}");
+                return;
             }
+        };
+
+        let fallback_msg = match fallback {
+            Some(fallback) => format!("generation {fallback}"),
+            None => "the base image".to_owned(),
+        };
+
+        notify::emit(
+            cfg,
+            Event {
+                name: "image_quarantined",
+                triplet,
+                message: format!(
+                    "Quarantined generation {generation} of {triplet}. New machines start from {fallback_msg}"
+                ),
+                details: vec![
+                    ("RUNNER_NAME", runner_name.to_owned()),
+                    ("GENERATION", generation.to_string()),
+                    (
+                        "FALLBACK_GENERATION",
+                        fallback.map(|f| f.to_string()).unwrap_or_default(),
+                    ),
+                ],
+            },
+        );
+    }
+
+    /// Get a snapshot of all machines, the RAM they use and the demand for them
+    pub fn status(&self) -> ManagerStatus {
+        let ram_total = self.config.get().host.ram.bytes();
+        let demand = self.demand.lock().unwrap().clone();
+        let machines = self.machines();
+
+        let machine_infos: Vec<MachineInfo> = machines
+            .values()
+            .flat_map(|triplet_machines| triplet_machines.iter())
+            .map(|machine| machine.info())
+            .collect();
+
+        let ram_used = machine_infos.iter().map(|info| info.ram_consumed).sum();
+
+        let triplets: HashSet<&Triplet> = demand.keys().chain(machines.keys()).collect();
+
+        let mut supply: Vec<Supply> = triplets
+            .into_iter()
+            .map(|triplet| {
+                let triplet_machines = machines.get(triplet).map(Vec::as_slice).unwrap_or_default();
+
+                Supply {
+                    triplet: triplet.to_string(),
+                    demand: demand.get(triplet).copied().unwrap_or_default(),
+                    available: triplet_machines
+                        .iter()
+                        .filter(|machine| {
+                            machine.status().is_available() && !machine.is_smoke_test()
+                        })
+                        .count(),
+                    machines: triplet_machines.len(),
+                }
+            })
+            .collect();
+
+        supply.sort_by(|a, b| a.triplet.cmp(&b.triplet));
+
+        let drain = self.drain.lock().unwrap();
+
+        let mut drained_machines: Vec<String> =
+            drain.machines.iter().map(Triplet::to_string).collect();
+
+        drained_machines.sort();
+
+        ManagerStatus {
+            machines: machine_infos,
+            supply,
+            ram_total,
+            ram_used,
+            ram_available: ram_total.saturating_sub(ram_used),
+            host_drained: drain.host,
+            drained_machines,
         }
     }
 
+    /// Get the runner names of all machines that have not stopped yet
+    fn runner_names(&self) -> HashSet<String> {
+        self.machines()
+            .values()
+            .flat_map(|machines_vec| machines_vec.iter())
+            .map(|machine| machine.runner_name().to_owned())
+            .collect()
+    }
+
     /// Perform a periodic sweep on the machines.
     ///
     /// This means getting the list of runners from the API,
-    /// updating the state of our local runner structures and
-    /// killing machines that failed to register as runner;
+    /// updating the state of our local runner structures,
+    /// killing machines that failed to register as runner,
+    /// refilling the pools of idle machines and
+    /// cleaning up the run dirs of stopped machines.
+    /// A sweep can be requested early via `sweep_soon()`.
     pub async fn janitor(&self) -> std::io::Result<()> {
         loop {
             self.sweep().await;
+            self.apply_demand();
+
+            retention::enforce(&self.config.get(), || self.runner_names());
 
-            tokio::time::sleep(std::time::Duration::from_secs(15 * 60)).await;
+            tokio::select! {
+                _ = tokio::time::sleep(std::time::Duration::from_secs(15 * 60)) => {}
+                _ = self.sweep_now.notified() => debug!("Sweeping on request"),
+            }
         }
     }
 }
@@ -375,8 +987,35 @@ impl Rescheduler {
     /// Trigger a re-schedule on the underlying `Manager`.
     ///
     /// This should be called whenever a machine exits so that new ones can be spawned
-    /// in it's place
+    /// in it's place.
+    /// This includes refilling the pools of idle machines, so that they do not
+    /// have to wait for the next demand update or janitor sweep.
+    /// Machine types that failed to start are only refilled after a backoff
+    /// (see `failed_to_start()`).
     pub fn reschedule(&self) {
-        self.manager.reschedule();
+        self.manager.apply_demand();
+    }
+
+    /// Record that a machine of type `triplet` failed to start
+    ///
+    /// New machines of this type are only started again once a backoff expired.
+    pub fn failed_to_start(&self, triplet: &Triplet) {
+        let mut start_backoff = self.manager.start_backoff.lock().unwrap();
+        let (failures, last) = start_backoff
+            .entry(triplet.clone())
+            .or_insert((0, Instant::now()));
+
+        *failures += 1;
+        *last = Instant::now();
+    }
+
+    /// Hold `machine` for `duration` if its repository has RAM left in its `max_held_ram` quota
+    pub fn hold(
+        &self,
+        machine: &Arc<Machine>,
+        max_held_ram: Option<u64>,
+        duration: Duration,
+    ) -> Result<(), String> {
+        self.manager.hold(machine, max_held_ram, duration)
     }
 }
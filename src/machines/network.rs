@@ -0,0 +1,257 @@
+use std::ffi::OsString;
+use std::fs::File;
+use std::net::{Ipv4Addr, TcpListener};
+use std::os::fd::{AsRawFd, RawFd};
+
+use crate::config::{MachineConfig, NetworkInterface, PortForward, SocketMode};
+
+use super::egress::EGRESS_PROXY_GUEST_ADDR;
+use super::mac_pool::Lease;
+
+// The options for the qemu user mode network stack (slirp).
+const USER_NETDEV_OPTIONS: &str = "ipv4=on,ipv6=on,ipv6-net=::/0";
+
+// The command qemu runs (in the run dir) for each connection to the egress proxy.
+const EGRESS_PROXY_CMD: &str = "socat STDIO UNIX-CONNECT:egress.sock";
+
+// The address the Forrest API is available at from inside the guest.
+const API_GUEST_ADDR: &str = "10.0.2.101:80";
+
+/// The URL of the Forrest API from inside the guest
+pub(super) const API_GUEST_URL: &str = "http://10.0.2.101";
+
+// The command qemu runs (in the run dir) for each connection to the Forrest API.
+const API_CMD: &str = "socat STDIO UNIX-CONNECT:api.sock";
+
+/// The network setup of a machine
+///
+/// This contains the qemu arguments for the uplink and all additional network
+/// interfaces of a machine, as well as the resources that have to be kept
+/// around while the machine is running.
+pub(super) struct Network {
+    args: Vec<OsString>,
+    restricted: bool,
+    uses_egress_proxy: bool,
+    forwards_api: bool,
+
+    // Pairs of (guest port, host port) that are forwarded from the host.
+    port_forwards: Vec<(u16, u16)>,
+
+    // File descriptors that are inherited by the qemu process,
+    // like those of macvtap devices.
+    fds: Vec<File>,
+}
+
+impl Network {
+    /// Assemble the network setup for a machine
+    ///
+    /// # Arguments
+    ///
+    /// * `machine_config` - The config of the machine to set up the network for.
+    /// * `virtio_suffix` - How to attach virtio devices: `pci` or `device` (mmio).
+    /// * `leases` - The addresses assigned to the uplink and the network interfaces
+    ///   (in this order) by `RunDir`.
+    pub(super) fn new(
+        machine_config: &MachineConfig,
+        virtio_suffix: &str,
+        leases: &[Option<Lease>],
+    ) -> std::io::Result<Self> {
+        let mut network = Self {
+            args: Vec::new(),
+            restricted: machine_config.egress.is_some(),
+            uses_egress_proxy: false,
+            forwards_api: false,
+            port_forwards: Vec::new(),
+            fds: Vec::new(),
+        };
+
+        // Ports can only be forwarded by the qemu user mode network stack.
+        let uplink_forwardable = matches!(machine_config.uplink, NetworkInterface::User);
+
+        if !machine_config.port_forwards.is_empty() && !uplink_forwardable {
+            let msg = "Port forwarding is only supported with a `user` uplink";
+            return Err(std::io::Error::other(msg));
+        }
+
+        let mut leases = leases.iter();
+
+        network.add_interface(
+            &machine_config.uplink,
+            "uplink",
+            virtio_suffix,
+            &machine_config.port_forwards,
+            leases.next().and_then(|lease| lease.as_ref()),
+        )?;
+
+        for (idx, ni) in machine_config.network_interfaces.iter().enumerate() {
+            let lease = leases.next().and_then(|lease| lease.as_ref());
+
+            network.add_interface(ni, &format!("nic-{idx}"), virtio_suffix, &[], lease)?;
+        }
+
+        Ok(network)
+    }
+
+    fn add_interface(
+        &mut self,
+        ni: &NetworkInterface,
+        id: &str,
+        virtio_suffix: &str,
+        port_forwards: &[PortForward],
+        lease: Option<&Lease>,
+    ) -> std::io::Result<()> {
+        let mut netdev_arg = OsString::new();
+
+        let mac = match ni {
+            NetworkInterface::None => return Ok(()),
+            NetworkInterface::User => {
+                netdev_arg.push(format!("user,id={id},{USER_NETDEV_OPTIONS}"));
+
+                // Make the per-machine API socket available to the guest.
+                // qemu spawns a socat process per connection to the API.
+                netdev_arg.push(format!(",guestfwd=tcp:{API_GUEST_ADDR}-cmd:{API_CMD}"));
+
+                self.forwards_api = true;
+
+                // Forward ports from the host loopback interface to the guest.
+                for pf in port_forwards {
+                    let host = match pf.host {
+                        Some(host) => host,
+                        None => free_port()?,
+                    };
+
+                    netdev_arg.push(format!(",hostfwd=tcp:127.0.0.1:{host}-:{}", pf.guest));
+
+                    self.port_forwards.push((pf.guest, host));
+                }
+
+                // Isolate the guest from the outside world and only allow
+                // connections via the egress proxy, which checks the destinations
+                // against an allowlist.
+                // qemu spawns a socat process per connection to the proxy.
+                if self.restricted {
+                    netdev_arg.push(format!(
+                        ",restrict=on,guestfwd=tcp:{EGRESS_PROXY_GUEST_ADDR}-cmd:{EGRESS_PROXY_CMD}"
+                    ));
+
+                    self.uses_egress_proxy = true;
+                }
+
+                // The user mode network is private to the machine,
+                // so there is no need to allocate a unique MAC address.
+                None
+            }
+            NetworkInterface::Vde(vde) => {
+                netdev_arg.push(format!("vde,id={id},sock="));
+                netdev_arg.push(vde.path.as_os_str());
+
+                lease.map(|lease| lease.mac())
+            }
+            NetworkInterface::Tap(tap) => {
+                // Let qemu create a tap device and attach it to the bridge
+                // using the (setuid) bridge helper.
+                netdev_arg.push(format!("bridge,id={id},br={}", tap.bridge));
+
+                if let Some(helper) = &tap.helper {
+                    netdev_arg.push(",helper=");
+                    netdev_arg.push(helper.as_os_str());
+                }
+
+                lease.map(|lease| lease.mac())
+            }
+            NetworkInterface::Macvtap(macvtap) => {
+                // A macvtap interface has a character device named after its
+                // interface index that we open and pass to qemu.
+                // The guest has to use the MAC address of the macvtap interface.
+                let sys_path = format!("/sys/class/net/{}", macvtap.interface);
+                let ifindex = std::fs::read_to_string(format!("{sys_path}/ifindex"))?;
+                let address = std::fs::read_to_string(format!("{sys_path}/address"))?;
+
+                let tap = File::options()
+                    .read(true)
+                    .write(true)
+                    .open(format!("/dev/tap{}", ifindex.trim()))?;
+
+                netdev_arg.push(format!("tap,id={id},fd={}", tap.as_raw_fd()));
+
+                self.fds.push(tap);
+
+                Some(address.trim().to_owned())
+            }
+            NetworkInterface::Socket(socket) => {
+                let mode = match socket.mode {
+                    SocketMode::Connect => "connect",
+                    SocketMode::Listen => "listen",
+                    SocketMode::Mcast => "mcast",
+                };
+
+                netdev_arg.push(format!("socket,id={id},{mode}={}", socket.address));
+
+                lease.map(|lease| lease.mac())
+            }
+            NetworkInterface::Stream(stream) => {
+                let server = if stream.server { "on" } else { "off" };
+
+                netdev_arg.push(format!(
+                    "stream,id={id},server={server},addr.type=unix,addr.path="
+                ));
+                netdev_arg.push(stream.path.as_os_str());
+
+                lease.map(|lease| lease.mac())
+            }
+        };
+
+        let mut device_arg = format!("virtio-net-{virtio_suffix},netdev={id}");
+
+        if let Some(mac) = mac {
+            device_arg.push_str(&format!(",mac={mac}"));
+        }
+
+        self.args.push("-netdev".into());
+        self.args.push(netdev_arg);
+        self.args.push("-device".into());
+        self.args.push(device_arg.into());
+
+        Ok(())
+    }
+
+    /// Does the network setup rely on the egress proxy?
+    ///
+    /// This is the case if egress filtering is configured and the machine uses
+    /// the qemu user mode network stack.
+    pub(super) fn uses_egress_proxy(&self) -> bool {
+        self.uses_egress_proxy
+    }
+
+    /// Is the per-machine API socket forwarded into the guest?
+    ///
+    /// This is the case if the machine uses the qemu user mode network stack.
+    pub(super) fn forwards_api(&self) -> bool {
+        self.forwards_api
+    }
+
+    /// The ports forwarded from the host to the guest as (guest port, host port) pairs
+    pub(super) fn port_forwards(&self) -> &[(u16, u16)] {
+        &self.port_forwards
+    }
+
+    /// The arguments to pass to qemu
+    pub(super) fn args(&self) -> &[OsString] {
+        &self.args
+    }
+
+    /// The file descriptors that have to be inherited by the qemu process
+    pub(super) fn inherited_fds(&self) -> Vec<RawFd> {
+        self.fds.iter().map(|fd| fd.as_raw_fd()).collect()
+    }
+}
+
+/// Find a currently unused TCP port on the host loopback interface
+///
+/// The port is only reserved until this function returns,
+/// so there is a small window in which another process could claim it.
+fn free_port() -> std::io::Result<u16> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+
+    Ok(listener.local_addr()?.port())
+}
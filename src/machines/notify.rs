@@ -0,0 +1,51 @@
+use log::{error, warn};
+use tokio::process::Command;
+
+use super::triplet::Triplet;
+use crate::config::ConfigFile;
+
+/// Something noteworthy that happened to a machine type and needs attention
+pub(super) struct Event<'a> {
+    /// A short identifier for the kind of event, e.g. `image_quarantined`
+    pub(super) name: &'static str,
+    pub(super) triplet: &'a Triplet,
+    /// A human readable description of the event
+    pub(super) message: String,
+    /// Additional details, passed as `FORREST_<KEY>` environment variables
+    pub(super) details: Vec<(&'static str, String)>,
+}
+
+/// Log an event and run the `host.notify_command` (if any) for it
+///
+/// The command is run in the background and gets the event in its environment
+/// as `FORREST_EVENT`, `FORREST_MACHINE`, `FORREST_MESSAGE` and the
+/// event-specific details.
+pub(super) fn emit(cfg: &ConfigFile, event: Event) {
+    warn!("{}", event.message);
+
+    let notify_command = match &cfg.host.notify_command {
+        Some(notify_command) => notify_command,
+        None => return,
+    };
+
+    let mut cmd = Command::new(notify_command);
+
+    cmd.env("FORREST_EVENT", event.name)
+        .env("FORREST_MACHINE", event.triplet.to_string())
+        .env("FORREST_MESSAGE", &event.message);
+
+    for (key, value) in &event.details {
+        cmd.env(format!("FORREST_{key}"), value);
+    }
+
+    let name = event.name;
+    let command = notify_command.display().to_string();
+
+    tokio::spawn(async move {
+        match cmd.status().await {
+            Ok(status) if status.success() => {}
+            Ok(status) => error!("Notification command {command} for {name} exited with {status}"),
+            Err(e) => error!("Failed to run notification command {command} for {name}: {e}"),
+        }
+    });
+}
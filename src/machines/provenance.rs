@@ -0,0 +1,109 @@
+use std::fs::read_to_string;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use octocrab::models::workflows::Job;
+use octocrab::models::{JobId, RunId};
+use octocrab::Octocrab;
+use serde::{Deserialize, Serialize};
+
+use super::triplet::Triplet;
+
+#[derive(Clone, Deserialize)]
+pub(super) struct Account {
+    pub(super) login: String,
+}
+
+/// The parts of a GitHub workflow run we are interested in
+#[derive(Clone, Deserialize)]
+pub(super) struct WorkflowRun {
+    pub(super) name: Option<String>,
+    pub(super) head_branch: Option<String>,
+    pub(super) head_sha: String,
+    pub(super) actor: Option<Account>,
+    pub(super) triggering_actor: Option<Account>,
+}
+
+/// Where a persisted machine image came from
+///
+/// This is stored as `<machine>.<n>.json` next to the image of each generation.
+#[derive(Serialize, Deserialize)]
+pub(super) struct Provenance {
+    pub(super) machine: String,
+    pub(super) generation: Option<u32>,
+    pub(super) runner_name: String,
+    pub(super) run_id: Option<RunId>,
+    pub(super) job_id: Option<JobId>,
+    pub(super) workflow: Option<String>,
+    pub(super) head_branch: Option<String>,
+    pub(super) head_sha: Option<String>,
+    pub(super) config_hash: String,
+    pub(super) started: String,
+    pub(super) persisted: String,
+
+    /// The image the machine was started from
+    pub(super) base_image: PathBuf,
+
+    /// The generation of this machine type the machine was started from (if any)
+    #[serde(default)]
+    pub(super) base_generation: Option<u32>,
+
+    /// The provenance of `base_image` if it was persisted by Forrest
+    pub(super) base: Option<Box<Provenance>>,
+}
+
+/// Get information about the workflow run `run_id`
+pub(super) async fn workflow_run(
+    octocrab: &Octocrab,
+    triplet: &Triplet,
+    run_id: RunId,
+) -> octocrab::Result<WorkflowRun> {
+    let route = format!(
+        "/repos/{}/{}/actions/runs/{run_id}",
+        triplet.owner(),
+        triplet.repository()
+    );
+
+    octocrab.get(route, None::<&()>).await
+}
+
+/// Get the current state of the job `job_id`
+pub(super) async fn job(
+    octocrab: &Octocrab,
+    triplet: &Triplet,
+    job_id: JobId,
+) -> octocrab::Result<Job> {
+    let route = format!(
+        "/repos/{}/{}/actions/jobs/{job_id}",
+        triplet.owner(),
+        triplet.repository()
+    );
+
+    octocrab.get(route, None::<&()>).await
+}
+
+impl Provenance {
+    /// Read the provenance of a persisted image from its sidecar file
+    ///
+    /// Returns `None` for images without provenance, like seed images or
+    /// images persisted by older versions of Forrest.
+    pub(super) fn read(path: &Path) -> std::io::Result<Option<Self>> {
+        let content = match read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let provenance = serde_json::from_str(&content).map_err(std::io::Error::other)?;
+
+        Ok(Some(provenance))
+    }
+
+    pub(super) fn to_json(&self) -> std::io::Result<String> {
+        serde_json::to_string_pretty(self).map_err(std::io::Error::other)
+    }
+
+    pub(super) fn write(&self, path: &Path) -> std::io::Result<()> {
+        std::fs::write(path, self.to_json()?)
+    }
+}
@@ -0,0 +1,360 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{read_dir, remove_dir_all, remove_file, symlink_metadata};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use log::{debug, error, info};
+
+use super::run_dir;
+use super::triplet::Triplet;
+use crate::config::{ConfigFile, Retention};
+
+// Files that are normally removed once a machine stops,
+// but are left behind if Forrest does not exit cleanly.
+const LEFTOVER_FILES: &[&str] = &["disk.img", "cloud-init.img", "job-config.img"];
+
+// The marker in the names of partially uploaded artifacts.
+const ARTIFACT_TMP_MARKER: &str = ".tmp-frst-";
+
+/// A run dir of a machine that is no longer running
+struct StoppedRun {
+    path: PathBuf,
+    runner_name: String,
+    triplet: Triplet,
+    modified: SystemTime,
+    size: u64,
+}
+
+/// Get the size of all files in a directory (recursively)
+fn dir_size(path: &Path) -> std::io::Result<u64> {
+    let mut size = 0;
+
+    for entry in read_dir(path)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+
+        size += match meta.is_dir() {
+            true => dir_size(&entry.path())?,
+            false => meta.len(),
+        };
+    }
+
+    Ok(size)
+}
+
+/// Remove a file and treat it not existing as success
+fn remove_if_exists(path: &Path) -> std::io::Result<bool> {
+    match remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Remove partially uploaded artifacts below `path`
+fn remove_artifact_tmp_files(path: &Path) -> std::io::Result<()> {
+    let entries = match read_dir(path) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    for entry in entries {
+        let entry = entry?;
+        let entry_path = entry.path();
+
+        if entry.file_type()?.is_dir() {
+            remove_artifact_tmp_files(&entry_path)?;
+        } else if entry
+            .file_name()
+            .to_string_lossy()
+            .contains(ARTIFACT_TMP_MARKER)
+        {
+            remove_file(&entry_path)?;
+
+            info!("Removed partial artifact upload {}", entry_path.display());
+        }
+    }
+
+    Ok(())
+}
+
+impl StoppedRun {
+    fn new(path: PathBuf) -> Option<Self> {
+        // The run dir is located at `runs/<owner>/<repository>/<machine>/<runner>`.
+        let mut components = path.iter().rev().map(|c| c.to_string_lossy());
+
+        let runner_name = components.next()?.into_owned();
+        let machine_name = components.next()?;
+        let repository = components.next()?;
+        let owner = components.next()?;
+
+        let triplet = Triplet::new(owner, repository, machine_name);
+
+        // The run dir is modified for the last time when the disk image is
+        // removed after the machine stopped.
+        let modified = match symlink_metadata(&path).and_then(|meta| meta.modified()) {
+            Ok(modified) => modified,
+            Err(e) => {
+                error!("Failed to get modification time of {}: {e}", path.display());
+                return None;
+            }
+        };
+
+        Some(Self {
+            path,
+            runner_name,
+            triplet,
+            modified,
+            size: 0,
+        })
+    }
+
+    /// Remove files that should have been removed when the machine stopped
+    ///
+    /// Their presence means that Forrest did not get to clean up after the
+    /// machine, e.g. because it crashed.
+    /// In this case there may also be partial artifact uploads left over.
+    fn remove_leftovers(&self, cfg: &ConfigFile) -> std::io::Result<()> {
+        let mut found_leftovers = false;
+
+        for name in LEFTOVER_FILES {
+            let path = self.path.join(name);
+
+            if remove_if_exists(&path)? {
+                info!("Removed leftover file {}", path.display());
+                found_leftovers = true;
+            }
+        }
+
+        if found_leftovers {
+            self.remove_artifact_tmp_files(cfg)?;
+        }
+
+        Ok(())
+    }
+
+    /// Remove partial uploads from the artifact stores of this run
+    ///
+    /// Only stores that are specific to this run (with a `<RUNNER_NAME>` in
+    /// their path) are cleaned up, as uploads by other machines may still be
+    /// in progress in shared stores.
+    fn remove_artifact_tmp_files(&self, cfg: &ConfigFile) -> std::io::Result<()> {
+        let machine_config = cfg
+            .repositories
+            .get(self.triplet.owner())
+            .and_then(|repos| repos.get(self.triplet.repository()))
+            .and_then(|repo| repo.machines.get(self.triplet.machine_name()));
+
+        let artifacts = match machine_config {
+            Some(mc) => &mc.artifacts,
+            None => return Ok(()),
+        };
+
+        for artifact in artifacts {
+            if !artifact.path.contains("<RUNNER_NAME>") {
+                continue;
+            }
+
+            let path = artifact.path.replace("<RUNNER_NAME>", &self.runner_name);
+
+            remove_artifact_tmp_files(Path::new(&path))?;
+        }
+
+        Ok(())
+    }
+
+    fn remove(&self, cfg: &ConfigFile, reason: &str) {
+        if let Err(e) = self.remove_artifact_tmp_files(cfg) {
+            error!(
+                "Failed to remove partial artifact uploads of {}: {e}",
+                self.runner_name
+            );
+        }
+
+        match remove_dir_all(&self.path) {
+            Ok(()) => info!("Removed run dir {} ({reason})", self.path.display()),
+            Err(e) => error!("Failed to remove run dir {}: {e}", self.path.display()),
+        }
+    }
+}
+
+/// Pick the runs to remove to satisfy the retention policy
+///
+/// `runs` must be sorted from newest to oldest.
+/// Returns the indices of the runs to remove and the reason for removal.
+fn expired(
+    runs: &[StoppedRun],
+    retention: &Retention,
+    now: SystemTime,
+) -> Vec<(usize, &'static str)> {
+    let mut expired = Vec::new();
+    let mut per_triplet: HashMap<&Triplet, usize> = HashMap::new();
+    let mut total_size = 0;
+    let mut size_exceeded = false;
+
+    for (index, run) in runs.iter().enumerate() {
+        let age = now.duration_since(run.modified).unwrap_or_default();
+
+        let count = per_triplet.entry(&run.triplet).or_default();
+        *count += 1;
+
+        let reason = if retention.max_age.is_some_and(|max_age| age > max_age) {
+            Some("max_age")
+        } else if retention
+            .max_count
+            .is_some_and(|max_count| *count > max_count)
+        {
+            Some("max_count")
+        } else if size_exceeded
+            || retention
+                .max_size
+                .as_ref()
+                .is_some_and(|max_size| total_size + run.size > max_size.bytes())
+        {
+            // Once the limit is exceeded all older runs are removed,
+            // even if some of them would still fit.
+            size_exceeded = true;
+            Some("max_size")
+        } else {
+            None
+        };
+
+        match reason {
+            Some(reason) => {
+                // Removed runs do not count towards the limit of their triplet.
+                *count -= 1;
+                expired.push((index, reason));
+            }
+            None => total_size += run.size,
+        }
+    }
+
+    expired
+}
+
+/// Clean up the run dirs of machines that are no longer running
+///
+/// This removes files that were left behind because Forrest did not stop
+/// cleanly and enforces the `host.retention` policy.
+/// Run dirs of the machines returned by `running` are never touched.
+pub(super) fn enforce(cfg: &ConfigFile, running: impl FnOnce() -> HashSet<String>) {
+    let run_dirs = match run_dir::all(&cfg.host.base_dir) {
+        Ok(run_dirs) => run_dirs,
+        Err(e) => {
+            error!("Failed to list run dirs: {e}");
+            return;
+        }
+    };
+
+    // The run dirs are listed before checking which machines are running,
+    // because the run dir of a new machine is only created after it was
+    // added to the list of machines.
+    let running = running();
+
+    let mut runs: Vec<StoppedRun> = run_dirs
+        .into_iter()
+        .filter_map(StoppedRun::new)
+        .filter(|run| !running.contains(&run.runner_name))
+        .collect();
+
+    for run in runs.iter_mut() {
+        if let Err(e) = run.remove_leftovers(cfg) {
+            error!("Failed to clean up run dir {}: {e}", run.path.display());
+        }
+
+        run.size = match dir_size(&run.path) {
+            Ok(size) => size,
+            Err(e) => {
+                error!("Failed to get size of run dir {}: {e}", run.path.display());
+                0
+            }
+        };
+    }
+
+    // Newest first, so that the oldest ones are removed.
+    runs.sort_by_key(|run| std::cmp::Reverse(run.modified));
+
+    let expired = expired(&runs, &cfg.host.retention, SystemTime::now());
+
+    debug!(
+        "Removing {} of {} stopped run dirs",
+        expired.len(),
+        runs.len()
+    );
+
+    for (index, reason) in expired {
+        runs[index].remove(cfg, reason);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::time::{Duration, SystemTime};
+
+    use super::{expired, StoppedRun};
+    use crate::config::Retention;
+    use crate::machines::Triplet;
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    fn run(machine: &str, age_hours: u32, size: u64, now: SystemTime) -> StoppedRun {
+        StoppedRun {
+            path: PathBuf::new(),
+            runner_name: String::new(),
+            triplet: Triplet::new("owner", "repo", machine),
+            modified: now - HOUR * age_hours,
+            size,
+        }
+    }
+
+    #[test]
+    fn retention_policy() {
+        let now = SystemTime::now();
+
+        // Sorted from newest to oldest.
+        let runs = [
+            run("build", 0, 10, now),
+            run("test", 1, 10, now),
+            run("build", 2, 10, now),
+            run("build", 3, 10, now),
+            run("test", 4, 1, now),
+            run("test", 48, 1, now),
+        ];
+
+        let no_limits = Retention::default();
+        assert!(expired(&runs, &no_limits, now).is_empty());
+
+        let max_age = Retention {
+            max_age: Some(24 * HOUR),
+            ..Default::default()
+        };
+        assert_eq!(expired(&runs, &max_age, now), [(5, "max_age")]);
+
+        let max_count = Retention {
+            max_count: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(
+            expired(&runs, &max_count, now),
+            [(3, "max_count"), (5, "max_count")]
+        );
+
+        // The oldest small run would still fit, but is removed anyways.
+        let max_size = Retention {
+            max_size: Some(yaml_serde::from_str("25B").unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(
+            expired(&runs, &max_size, now),
+            [
+                (2, "max_size"),
+                (3, "max_size"),
+                (4, "max_size"),
+                (5, "max_size")
+            ]
+        );
+    }
+}
@@ -1,28 +1,74 @@
-use std::fs::{copy, create_dir_all, File};
+use std::fs::{copy, create_dir_all, read_dir, File};
 use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
 
+use chrono::Local;
 use log::{debug, error, info, warn};
+use octocrab::models::workflows::Conclusion;
+use octocrab::models::{JobId, RunId};
 use reflink_copy::reflink;
 
-use crate::config::SeedBasePolicy;
+use crate::config::{
+    ConfigFile, NetworkInterface, PersistConflictPolicy, PersistOn, Promotion, SeedBasePolicy,
+};
 
 use super::config_fs::ConfigFs;
+use super::egress::EGRESS_PROXY_GUEST_ADDR;
+use super::generations::Generations;
+use super::mac_pool::{self, Lease};
 use super::machine::Machine;
 use super::manager::Machines;
+use super::network::API_GUEST_URL;
+use super::notify::{self, Event};
+use super::provenance::{Provenance, WorkflowRun};
+use super::ssh_access::SSH_KEYS_FILE;
+use super::triplet::Triplet;
 
 const JOB_CONFIG_IMAGE_SIZE: u64 = 1024 * 1024;
 const JOB_CONFIG_IMAGE_LABEL: &str = "JOBDATA";
 const CLOUD_INIT_IMAGE_SIZE: u64 = 1024 * 1024;
 const CLOUD_INIT_IMAGE_LABEL: &str = "CIDATA";
 
+// The file a smoke test check script leaves in the job config filesystem
+// if the candidate image passed the check.
+const CHECK_PASSED_FILE: &str = "check-passed";
+
+/// A candidate image a machine was started from to test it before it is promoted
+struct SmokeTest {
+    triplet: Triplet,
+    generation: u32,
+    /// Run a check script instead of waiting for the runner to register
+    check: bool,
+}
+
+/// Whether a job asked for its disk image to be persisted
+pub(super) enum PersistRequest {
+    /// The job did not leave a persist file
+    None,
+    /// The job left a persist file containing the persistence token
+    Valid,
+    /// The job left a persist file that does not match the persistence token
+    Rejected,
+}
+
 pub(super) struct RunDir {
     run_dir: PathBuf,
     disk: PathBuf,
-    machine_image: PathBuf,
+    generations: Generations,
+    generation: Option<u32>,
+    newest_at_start: Option<u32>,
+    keep_generations: u32,
+    persist_on: PersistOn,
+    persist_conflict: PersistConflictPolicy,
+    provenance: Provenance,
+    smoke_test: Option<SmokeTest>,
+    promotion_required: bool,
     _cloud_init: ConfigFs,
     job_config: Option<ConfigFs>,
     persistence_token: Option<String>,
+
+    // The MAC addresses are returned to the pool once these are dropped.
+    leases: Vec<Option<Lease>>,
 }
 
 fn not_found_none<V>(res: std::io::Result<V>) -> std::io::Result<Option<V>> {
@@ -49,6 +95,89 @@ fn pick_newer<'p>(a: &'p Path, b: &'p Path) -> std::io::Result<&'p Path> {
     }
 }
 
+/// Get the run dirs of all machines
+///
+/// These are located in `<base_dir>/runs/<owner>/<repository>/<machine>/<runner>`.
+pub(super) fn all(base_dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut dirs = vec![base_dir.join("runs")];
+
+    for _level in 0..4 {
+        let mut children = Vec::new();
+
+        for dir in dirs {
+            let entries = match read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+
+            for entry in entries {
+                let entry = entry?;
+
+                if entry.file_type()?.is_dir() {
+                    children.push(entry.path());
+                }
+            }
+        }
+
+        dirs = children;
+    }
+
+    Ok(dirs)
+}
+
+/// Find the run dir of the machine with runner name `runner_name`
+///
+/// The run dirs are kept after a machine stopped, so this also finds
+/// machines that are no longer running.
+/// Returns the triplet of the machine along with the path.
+pub fn find(base_dir: &Path, runner_name: &str) -> std::io::Result<Option<(Triplet, PathBuf)>> {
+    let runner_name_valid = !runner_name.is_empty()
+        && runner_name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    if !runner_name_valid {
+        return Ok(None);
+    }
+
+    let run_dir = all(base_dir)?
+        .into_iter()
+        .find(|dir| dir.file_name().is_some_and(|name| name == runner_name));
+
+    let run_dir = match run_dir {
+        Some(run_dir) => run_dir,
+        None => return Ok(None),
+    };
+
+    // The three directories above the run dir are the machine triplet.
+    let mut components = run_dir
+        .iter()
+        .rev()
+        .skip(1)
+        .take(3)
+        .map(|c| c.to_string_lossy());
+
+    let machine_name = components.next().unwrap_or_default();
+    let repository = components.next().unwrap_or_default();
+    let owner = components.next().unwrap_or_default();
+
+    let triplet = Triplet::new(owner, repository, machine_name);
+
+    Ok(Some((triplet, run_dir)))
+}
+
+/// Is another machine already started from `candidate` to test it?
+fn smoke_test_running(machine: &Machine, machines: &Machines, candidate: u32) -> bool {
+    machines
+        .get(machine.triplet())
+        .into_iter()
+        .flatten()
+        // The machine itself is locked while its run dir is set up.
+        .filter(|other| other.runner_name() != machine.runner_name())
+        .any(|other| other.smoke_test_generation() == Some(candidate))
+}
+
 impl RunDir {
     /// Create a directory for a machine run and populate it to match our qemu arguments
     ///
@@ -56,6 +185,7 @@ impl RunDir {
     /// a `cloud-init.img` that contains cloud-init configuration and
     /// a `job-config.img` file that contains configuration for running the current job
     /// and is used for feedback from the machine after completion.
+    /// Machines using UEFI firmware also get an `efivars.fd` variable store.
     ///
     /// The disk file is based either on a previous run of this machine,
     /// a previous run of another machine (a base machine that generates images)
@@ -73,14 +203,23 @@ impl RunDir {
 
         let base_dir = &cfg.host.base_dir;
 
-        let machine_image = triplet.machine_image_path(base_dir);
+        let generations = Generations::new(triplet, base_dir);
+
+        // Make sure that images persisted by older versions of Forrest are part
+        // of a generation, so that they can be quarantined if they are broken.
+        generations.migrate()?;
+
+        let machine_image = generations.image()?;
+
+        // Used to detect other runs that persisted their image while this one ran.
+        let newest_at_start = generations.newest()?;
 
         let base_image = match &machine_config.base_machine {
             Some(base_triplet) if machines.contains_key(base_triplet) => {
                 info!("Delaying the startup of {machine} because its base {base_triplet} is currently running");
                 return Ok(None);
             }
-            Some(base_triplet) => base_triplet.machine_image_path(base_dir),
+            Some(base_triplet) => Generations::new(base_triplet, base_dir).image()?,
             None => match &machine_config.base_image {
                 Some(base_image) => base_image.clone(),
                 None => {
@@ -91,10 +230,28 @@ impl RunDir {
             },
         };
 
-        let image = match machine_config.use_base {
-            SeedBasePolicy::IfNewer => pick_newer(&base_image, &machine_image)?,
-            SeedBasePolicy::Always => &base_image,
-            SeedBasePolicy::Never => &machine_image,
+        // Only dedicated smoke test machines start from a candidate image,
+        // so that jobs never run on an untested image.
+        // A candidate image is tested by one machine at a time.
+        let candidate = match (machine.is_smoke_test(), &machine_config.promotion) {
+            (false, _) | (true, Promotion::None) => None,
+            (true, Promotion::Register | Promotion::Check(_)) => generations
+                .candidate()?
+                .filter(|candidate| !smoke_test_running(machine, machines, *candidate)),
+        };
+
+        if machine.is_smoke_test() && candidate.is_none() {
+            let msg = "There is no candidate image left to test";
+            return Err(std::io::Error::other(msg));
+        }
+
+        let candidate_image = candidate.map(|candidate| generations.path(candidate, "img"));
+
+        let image = match (&candidate_image, machine_config.use_base) {
+            (Some(candidate_image), _) => candidate_image,
+            (None, SeedBasePolicy::IfNewer) => pick_newer(&base_image, &machine_image)?,
+            (None, SeedBasePolicy::Always) => &base_image,
+            (None, SeedBasePolicy::Never) => &machine_image,
         };
 
         if !image.try_exists()? {
@@ -105,6 +262,59 @@ impl RunDir {
             return Ok(None);
         }
 
+        // Images persisted by Forrest have a provenance sidecar file,
+        // which becomes part of the provenance of the image persisted by this run.
+        let image_is_persisted = image == machine_image
+            || candidate.is_some()
+            || (image == base_image && machine_config.base_machine.is_some());
+
+        let generation = match image == machine_image {
+            true => generations.image_generation(image),
+            false => candidate,
+        };
+
+        let smoke_test = candidate.map(|generation| {
+            info!("Starting {machine} from candidate generation {generation} to test it");
+
+            SmokeTest {
+                triplet: triplet.clone(),
+                generation,
+                check: matches!(machine_config.promotion, Promotion::Check(_)),
+            }
+        });
+
+        // The check script replaces the job script for smoke tests.
+        let job_config_overrides: Vec<(&str, &Path)> =
+            match (&smoke_test, &machine_config.promotion) {
+                (Some(_), Promotion::Check(check)) => vec![("job.sh", check.script.as_path())],
+                _ => Vec::new(),
+            };
+
+        let base_provenance = match image_is_persisted {
+            true => Provenance::read(&image.with_extension("json")).unwrap_or_else(|err| {
+                error!("Failed to read provenance of {}: {err}", image.display());
+                None
+            }),
+            false => None,
+        };
+
+        let provenance = Provenance {
+            machine: triplet.to_string(),
+            generation: None,
+            runner_name: machine.runner_name().to_owned(),
+            run_id: None,
+            job_id: None,
+            workflow: None,
+            head_branch: None,
+            head_sha: None,
+            config_hash: cfg.hash.clone(),
+            started: Local::now().to_rfc3339(),
+            persisted: String::new(),
+            base_image: image.to_owned(),
+            base_generation: generation,
+            base: base_provenance.map(Box::new),
+        };
+
         let persistence_token = cfg
             .repositories
             .get(triplet.owner())
@@ -131,6 +341,44 @@ impl RunDir {
 
         let template = &machine_config.setup_template;
 
+        // Jobs that are restricted to an egress allowlist have to use the proxy.
+        let egress_proxy = match machine_config.egress {
+            Some(_) => format!("http://{EGRESS_PROXY_GUEST_ADDR}"),
+            None => String::new(),
+        };
+
+        // The API is forwarded into guests that use the qemu user mode network stack.
+        let api_url = match machine_config.uplink {
+            NetworkInterface::User => API_GUEST_URL,
+            _ => "",
+        };
+
+        // Assign MAC and (optionally) static IP addresses to the uplink and
+        // the network interfaces, so that they can be configured in the guest.
+        let leases = std::iter::once(&machine_config.uplink)
+            .chain(machine_config.network_interfaces.iter())
+            .map(|ni| {
+                ni.addressing()
+                    .map(|addressing| mac_pool::lease(addressing, base_dir))
+                    .transpose()
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        let address_substitutions: Vec<(String, String)> = {
+            let names = std::iter::once("UPLINK".to_owned())
+                .chain((0..machine_config.network_interfaces.len()).map(|idx| format!("NIC{idx}")));
+
+            names
+                .zip(leases.iter())
+                .flat_map(|(name, lease)| {
+                    let mac = lease.as_ref().map(|l| l.mac()).unwrap_or_default();
+                    let ip = lease.as_ref().and_then(|l| l.ip()).unwrap_or_default();
+
+                    [(format!("{name}_MAC"), mac), (format!("{name}_IP"), ip)]
+                })
+                .collect()
+        };
+
         let substitutions = {
             let mut sub = vec![
                 ("REPO_OWNER", triplet.owner()),
@@ -138,6 +386,8 @@ impl RunDir {
                 ("MACHINE_NAME", triplet.machine_name()),
                 ("JITCONFIG", encoded_jit_config.as_str()),
                 ("RUN_TOKEN", machine.run_token()),
+                ("EGRESS_PROXY", egress_proxy.as_str()),
+                ("FORREST_API_URL", api_url),
             ];
 
             let parameters = template
@@ -147,6 +397,12 @@ impl RunDir {
 
             sub.extend(parameters);
 
+            let addresses = address_substitutions
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()));
+
+            sub.extend(addresses);
+
             sub
         };
 
@@ -160,6 +416,7 @@ impl RunDir {
                 CLOUD_INIT_IMAGE_LABEL,
                 cloud_init_template_path,
                 &substitutions,
+                &[],
             )?
         };
 
@@ -173,14 +430,20 @@ impl RunDir {
                 JOB_CONFIG_IMAGE_LABEL,
                 job_config_template_path,
                 &substitutions,
+                &job_config_overrides,
             )?
         };
 
         // Copy a TPM state to the run dir _if_ one was prepared for the
         // machine type. This is optional. Continue if none is present.
-        let machine_tmp_state_path = triplet.machine_tmp_state_path(base_dir);
+        let machine_tpm_state = match candidate {
+            Some(candidate) if generations.path(candidate, "swtpm").try_exists()? => {
+                generations.path(candidate, "swtpm")
+            }
+            _ => generations.tpm_state()?,
+        };
         let tmp_state_path = run_dir.join("tpm.swtpm");
-        copy(machine_tmp_state_path, tmp_state_path).or_else(|err| match err.kind() {
+        copy(&machine_tpm_state, tmp_state_path).or_else(|err| match err.kind() {
             ErrorKind::NotFound => {
                 info!("Did not find TPM state for {machine}, continuing without TPM");
                 Ok(0)
@@ -188,13 +451,46 @@ impl RunDir {
             err => Err(err),
         })?;
 
+        // Copy the UEFI variable store to the run dir if the machine uses UEFI firmware.
+        // Prefer the variables persisted along with the image we boot from,
+        // since they may e.g. contain the boot entries for it.
+        // Fall back to the template from the machine config otherwise.
+        if let Some(firmware) = &machine_config.firmware {
+            let persisted_efivars = if let Some(candidate) = candidate {
+                Some(generations.path(candidate, "efivars"))
+            } else if image == machine_image {
+                Some(generations.efivars()?)
+            } else {
+                match &machine_config.base_machine {
+                    Some(base_triplet) => Some(Generations::new(base_triplet, base_dir).efivars()?),
+                    None => None,
+                }
+            };
+
+            let efivars = match persisted_efivars {
+                Some(pe) if pe.try_exists()? => pe,
+                _ => firmware.vars.clone(),
+            };
+
+            copy(efivars, run_dir.join("efivars.fd"))?;
+        }
+
         let dir = Self {
             run_dir,
-            machine_image,
             disk,
+            generations,
+            generation,
+            newest_at_start,
+            keep_generations: machine_config.image_generations,
+            persist_on: machine_config.persist_on,
+            persist_conflict: machine_config.persist_conflict,
+            provenance,
+            smoke_test,
+            promotion_required: machine_config.promotion != Promotion::None,
             _cloud_init,
             job_config: Some(job_config),
             persistence_token,
+            leases,
         };
 
         Ok(Some(dir))
@@ -204,23 +500,147 @@ impl RunDir {
         &self.run_dir
     }
 
-    /// Persist the disk image as new machine image if the correct persist file was written
-    pub(super) fn maybe_persist(&mut self) {
+    /// The generation of the machine image this machine started from
+    ///
+    /// This is `None` if the machine started from a base image instead.
+    pub(super) fn generation(&self) -> Option<u32> {
+        self.generation
+    }
+
+    /// The candidate generation this machine was started from to test it (if any)
+    pub(super) fn smoke_test_generation(&self) -> Option<u32> {
+        self.smoke_test
+            .as_ref()
+            .map(|smoke_test| smoke_test.generation)
+    }
+
+    /// Promote the candidate image if this machine tests it by registering as runner
+    pub(super) fn registered(&mut self) {
+        if self
+            .smoke_test
+            .as_ref()
+            .is_some_and(|smoke_test| !smoke_test.check)
+        {
+            self.pass_smoke_test();
+        }
+    }
+
+    fn pass_smoke_test(&mut self) {
+        let smoke_test = match self.smoke_test.take() {
+            Some(smoke_test) => smoke_test,
+            None => return,
+        };
+
+        let generation = smoke_test.generation;
+        let triplet = &smoke_test.triplet;
+
+        match self.generations.promote(generation) {
+            Ok(true) => info!("Promoted generation {generation} of {triplet} after a successful smoke test"),
+            Ok(false) => info!("Generation {generation} of {triplet} passed its smoke test, but a newer generation is already in use"),
+            Err(e) => error!("Failed to promote generation {generation} of {triplet}: {e}"),
+        }
+    }
+
+    /// Discard the candidate image this machine tests (if any)
+    pub(super) fn fail_smoke_test(&mut self, cfg: &ConfigFile, reason: &str) {
+        let smoke_test = match self.smoke_test.take() {
+            Some(smoke_test) => smoke_test,
+            None => return,
+        };
+
+        let generation = smoke_test.generation;
+        let triplet = &smoke_test.triplet;
+
+        if let Err(e) = self.generations.discard(generation) {
+            error!("Failed to discard generation {generation} of {triplet}: {e}");
+        }
+
+        notify::emit(
+            cfg,
+            Event {
+                name: "candidate_discarded",
+                triplet,
+                message: format!(
+                    "Discarded candidate generation {generation} of {triplet}: {reason}"
+                ),
+                details: vec![
+                    ("GENERATION", generation.to_string()),
+                    ("REASON", reason.to_owned()),
+                ],
+            },
+        );
+    }
+
+    /// Decide on the candidate image this machine tests once the machine stopped
+    ///
+    /// With a check script the candidate passes if the script left a `check-passed`
+    /// file in the job config filesystem.
+    /// Without one the machine should have registered as runner before it stopped.
+    pub(super) fn finish_smoke_test(&mut self, cfg: &ConfigFile) {
+        let check = match &self.smoke_test {
+            Some(smoke_test) => smoke_test.check,
+            None => return,
+        };
+
+        if !check {
+            self.fail_smoke_test(cfg, "The machine stopped before registering as runner");
+            return;
+        }
+
+        let inspector = match self.job_config.take().map(|jc| jc.inspect()) {
+            Some(Ok(inspector)) => inspector,
+            Some(Err(err)) => {
+                let reason = format!("Failed to inspect the job config image: {err}");
+                self.fail_smoke_test(cfg, &reason);
+                return;
+            }
+            None => {
+                self.fail_smoke_test(cfg, "The job config image was already inspected");
+                return;
+            }
+        };
+
+        match inspector.read_file(CHECK_PASSED_FILE, &mut []) {
+            Ok(()) => self.pass_smoke_test(),
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                self.fail_smoke_test(cfg, "The check script did not pass")
+            }
+            Err(err) => {
+                let reason = format!("Failed to read the check result: {err}");
+                self.fail_smoke_test(cfg, &reason);
+            }
+        }
+    }
+
+    /// The addresses assigned to the uplink and the network interfaces (in this order)
+    pub(super) fn leases(&self) -> &[Option<Lease>] {
+        &self.leases
+    }
+
+    /// Check if the job requested its disk image to be persisted
+    ///
+    /// This is the case if the job left a persist file containing the
+    /// persistence token in the job config.
+    /// Errors are returned if the persist file could not be checked.
+    pub(super) fn persist_requested(&mut self) -> std::io::Result<PersistRequest> {
         let persistence_token = match &self.persistence_token {
             Some(pt) => pt.as_bytes(),
-            None => return,
+            None => return Ok(PersistRequest::None),
         };
 
         let dds = self.disk.display();
-        let mds = self.machine_image.display();
 
-        let inspector = match self.job_config.take().unwrap().inspect() {
+        // The job config was already inspected to check the result of a smoke test.
+        let job_config = match self.job_config.take() {
+            Some(job_config) => job_config,
+            None => return Ok(PersistRequest::None),
+        };
+
+        let inspector = match job_config.inspect() {
             Ok(inspector) => inspector,
             Err(err) => {
-                error!(
-                    "Failed to inspect job config image. Will not persist {dds} to {mds}: {err}"
-                );
-                return;
+                let msg = format!("Failed to inspect job config image: {err}");
+                return Err(std::io::Error::other(msg));
             }
         };
 
@@ -230,37 +650,107 @@ impl RunDir {
             match inspector.read_file("persist", &mut buf) {
                 Ok(()) => buf,
                 Err(err) if err.kind() == ErrorKind::NotFound => {
-                    info!("Job did not leave a persist file. Will not persist {dds} to {mds}");
-                    return;
+                    info!("Job did not leave a persist file. Will not persist {dds}");
+                    return Ok(PersistRequest::None);
                 }
                 Err(err) => {
-                    error!("Failed to read persist file. Will not persist {dds} to {mds}: {err}");
-                    return;
+                    let msg = format!("Failed to read persist file: {err}");
+                    return Err(std::io::Error::other(msg));
                 }
             }
         };
 
         if persist_file_content != persistence_token {
             error!("Job left a persist file, but it does not match the token.");
-            error!("Will not persist {dds} to {mds}");
-            return;
+            error!("Will not persist {dds}");
+            return Ok(PersistRequest::Rejected);
         }
 
-        let machine_image_dir = self.machine_image.parent().unwrap();
+        Ok(PersistRequest::Valid)
+    }
 
-        if let Err(err) = std::fs::create_dir_all(machine_image_dir) {
-            let mdds = machine_image_dir.display();
+    /// Persist the disk image as new machine image
+    ///
+    /// Must only be called if the job requested it (see `persist_requested()`).
+    /// The job that ran on the machine is recorded in the provenance of the image.
+    /// With `persist_on: success` the `conclusion` of the job must be successful as well.
+    /// Returns the path of the persisted disk image or `None` if the image
+    /// should not be persisted.
+    /// Errors are returned if the image should have been persisted, but could not be.
+    pub(super) fn persist(
+        &mut self,
+        run_id: Option<RunId>,
+        job_id: Option<JobId>,
+        workflow_run: Option<&WorkflowRun>,
+        conclusion: Option<&Conclusion>,
+    ) -> std::io::Result<Option<PathBuf>> {
+        let dds = self.disk.display();
 
-            error!("Failed to create machine image dir {mdds}: {err}");
-            return;
+        // The UEFI variables and the TPM state belong to the disk image they
+        // were used with (e.g. they may contain boot entries or keys sealed to
+        // the state of the disk image), so they are persisted alongside it.
+        let efivars = self.run_dir.join("efivars.fd");
+        let tpm_state = self.run_dir.join("tpm.swtpm");
+
+        let files = [
+            ("img", self.disk.as_path()),
+            ("efivars", efivars.as_path()),
+            ("swtpm", tpm_state.as_path()),
+        ];
+
+        if self.persist_on == PersistOn::Success {
+            match conclusion {
+                Some(Conclusion::Success) => {}
+                Some(conclusion) => {
+                    info!("Job concluded with {conclusion:?}. Will not persist {dds}");
+                    return Ok(None);
+                }
+                None => {
+                    warn!("Could not determine the conclusion of the job. Will not persist {dds}");
+                    return Ok(None);
+                }
+            }
         }
 
-        if let Err(err) = std::fs::rename(&self.disk, &self.machine_image) {
-            error!("Failed to move image from {dds} to {mds}: {err}");
-            return;
+        let conflict = self.generations.persist_conflict(
+            self.persist_conflict,
+            self.newest_at_start,
+            self.generation,
+        );
+
+        match conflict {
+            Ok(None) => {}
+            Ok(Some(reason)) => {
+                warn!("Will not persist {dds}, because {reason}");
+                return Ok(None);
+            }
+            Err(err) => {
+                let msg = format!("Failed to check for conflicting generations: {err}");
+                return Err(std::io::Error::other(msg));
+            }
         }
 
-        info!("Persisted disk file {dds} as {mds}");
+        let provenance = &mut self.provenance;
+
+        provenance.run_id = run_id;
+        provenance.job_id = job_id;
+        provenance.workflow = workflow_run.and_then(|run| run.name.clone());
+        provenance.head_branch = workflow_run.and_then(|run| run.head_branch.clone());
+        provenance.head_sha = workflow_run.map(|run| run.head_sha.clone());
+        provenance.persisted = Local::now().to_rfc3339();
+
+        let candidate = self.promotion_required;
+
+        let generation =
+            self.generations
+                .persist(&files, self.keep_generations, provenance, candidate)?;
+
+        match candidate {
+            true => info!("Persisted disk file {dds} as candidate generation {generation}"),
+            false => info!("Persisted disk file {dds} as generation {generation}"),
+        }
+
+        Ok(Some(self.generations.path(generation, "img")))
     }
 }
 
@@ -282,5 +772,14 @@ impl Drop for RunDir {
             }
             Err(e) => error!("Failed to remove disk image {ds}: {e}"),
         }
+
+        // The machine can not be accessed via the SSH jump host anymore.
+        let ssh_keys = self.run_dir.join(SSH_KEYS_FILE);
+
+        match std::fs::remove_file(&ssh_keys) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => error!("Failed to remove {}: {e}", ssh_keys.display()),
+        }
     }
 }
@@ -0,0 +1,49 @@
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use log::info;
+use tokio::process::Command;
+
+const FALLOCATE_CMD: &str = "/usr/bin/fallocate";
+
+/// The number of bytes actually allocated on disk for a file
+fn allocated(path: &Path) -> std::io::Result<u64> {
+    // `blocks()` is always in units of 512 bytes, regardless of the
+    // block size of the filesystem.
+    Ok(path.metadata()?.blocks() * 512)
+}
+
+/// Deallocate the blocks of a disk image that only contain zeros
+///
+/// Guests dirty blocks all over their disk over time, even if the data in them
+/// is later deleted or zeroed.
+/// Punching holes where the disk image only contains zeros keeps persisted
+/// images from growing to their full size over many generations.
+/// The content of the image as seen by the guest is not changed,
+/// so this is safe to do while other machines start from the image.
+pub(super) async fn sparsify(image: &Path) -> std::io::Result<()> {
+    let before = allocated(image)?;
+
+    let status = Command::new(FALLOCATE_CMD)
+        .arg("--dig-holes")
+        .arg(image)
+        .status()
+        .await?;
+
+    if !status.success() {
+        let msg = format!("{FALLOCATE_CMD} exited with {status}");
+        return Err(std::io::Error::other(msg));
+    }
+
+    let after = allocated(image)?;
+
+    info!(
+        "Sparsified {}: {} MiB saved ({} MiB allocated before, {} MiB after)",
+        image.display(),
+        before.saturating_sub(after) / (1024 * 1024),
+        before / (1024 * 1024),
+        after / (1024 * 1024),
+    );
+
+    Ok(())
+}
@@ -0,0 +1,177 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{read_to_string, write};
+use std::io::ErrorKind;
+use std::path::Path;
+
+use octocrab::Octocrab;
+use serde::Deserialize;
+use tokio::io::{copy_bidirectional, join, stdin, stdout};
+use tokio::net::{TcpStream, UnixStream};
+
+use super::provenance::WorkflowRun;
+use super::run_dir;
+
+// Files in the run dir of a machine that are used by the `ssh-keys` and
+// `ssh-jump` commands, which run as separate processes.
+pub(super) const SSH_KEYS_FILE: &str = "ssh_keys";
+pub(super) const PORT_FORWARDS_FILE: &str = "port_forwards";
+
+#[derive(Deserialize)]
+struct PublicKey {
+    key: String,
+}
+
+/// Get the login and public SSH keys of the GitHub user that triggered a workflow run
+///
+/// Returns `None` if the run does not have an actor.
+pub(super) async fn run_actor_keys(
+    octocrab: &Octocrab,
+    run: &WorkflowRun,
+) -> octocrab::Result<Option<(String, Vec<String>)>> {
+    // Prefer the user that e.g. re-ran a job over the one that originally
+    // triggered the workflow run.
+    let login = match run.triggering_actor.as_ref().or(run.actor.as_ref()) {
+        Some(account) => account.login.clone(),
+        None => return Ok(None),
+    };
+
+    let keys: Vec<PublicKey> = octocrab
+        .get(format!("/users/{login}/keys"), None::<&()>)
+        .await?;
+
+    let keys = keys.into_iter().map(|pk| pk.key).collect();
+
+    Ok(Some((login, keys)))
+}
+
+/// Allow `login` to access the machine with the given `keys`
+pub(super) fn write_keys(run_dir: &Path, login: &str, keys: &[String]) -> std::io::Result<()> {
+    let content: String = keys.iter().map(|key| format!("{login} {key}\n")).collect();
+
+    write(run_dir.join(SSH_KEYS_FILE), content)
+}
+
+/// Record the (guest port, host port) pairs forwarded to a machine
+pub(super) fn write_port_forwards(
+    run_dir: &Path,
+    port_forwards: &[(u16, u16)],
+) -> std::io::Result<()> {
+    let content: String = port_forwards
+        .iter()
+        .map(|(guest, host)| format!("{guest} {host}\n"))
+        .collect();
+
+    write(run_dir.join(PORT_FORWARDS_FILE), content)
+}
+
+/// Read the `login key` lines of the SSH keys file in a run dir
+fn read_keys(run_dir: &Path) -> std::io::Result<Vec<(String, String)>> {
+    let content = match read_to_string(run_dir.join(SSH_KEYS_FILE)) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let keys = content
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .map(|(login, key)| (login.to_owned(), key.to_owned()))
+        .collect();
+
+    Ok(keys)
+}
+
+/// Print the `authorized_keys` lines for all users that may access a machine
+///
+/// This is meant to be used as `AuthorizedKeysCommand` in the `sshd` config.
+/// Every key is restricted to running `forrest ssh-jump` for its GitHub user.
+/// Only machines that are still running (i.e. have a shell socket) are
+/// considered, as the keys of stopped machines are removed with their disk.
+/// The run dirs are found in `base_dir`, so that the jump host user does
+/// not need access to the config file and the secrets in it.
+pub fn print_authorized_keys(base_dir: &Path) -> std::io::Result<()> {
+    let exe = std::env::current_exe()?;
+    let base_dir = base_dir.canonicalize()?;
+
+    let mut users: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+
+    for run_dir in run_dir::all(&base_dir)? {
+        if !run_dir.join("shell.sock").exists() {
+            continue;
+        }
+
+        for (login, key) in read_keys(&run_dir)? {
+            users.entry(login).or_default().insert(key);
+        }
+    }
+
+    for (login, keys) in users {
+        for key in keys {
+            println!(
+                "restrict,pty,command=\"{} ssh-jump {} {login}\" {key}",
+                exe.display(),
+                base_dir.display(),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Connect the standard input and output to a machine on behalf of `login`
+///
+/// The machine and port are taken from the `SSH_ORIGINAL_COMMAND` environment
+/// variable, which contains the command the user passed to `ssh`:
+///
+///   - `<runner name>` - Connect to the shell on the serial console.
+///   - `<runner name> <guest port>` - Connect to a port forwarded to the machine.
+pub async fn jump(base_dir: &Path, login: &str) -> std::io::Result<()> {
+    let original_command = std::env::var("SSH_ORIGINAL_COMMAND").unwrap_or_default();
+    let mut components = original_command.split_ascii_whitespace();
+
+    let runner_name = components.next().unwrap_or("");
+    let guest_port = components.next();
+
+    if runner_name.is_empty() {
+        let msg = "Usage: ssh <jump host> <runner name> [<guest port>]";
+        return Err(std::io::Error::other(msg));
+    }
+
+    // Use the same error for machines that do not exist and machines the
+    // user may not access to not leak which machines exist.
+    let not_found = || std::io::Error::other(format!("No accessible machine {runner_name}"));
+
+    let (_, run_dir) = run_dir::find(base_dir, runner_name)?.ok_or_else(not_found)?;
+
+    let has_access = read_keys(&run_dir)?.iter().any(|(l, _)| l == login);
+
+    if !has_access {
+        return Err(not_found());
+    }
+
+    let mut stdio = join(stdin(), stdout());
+
+    match guest_port {
+        None => {
+            let mut shell = UnixStream::connect(run_dir.join("shell.sock")).await?;
+            copy_bidirectional(&mut stdio, &mut shell).await?;
+        }
+        Some(guest_port) => {
+            let port_forwards = read_to_string(run_dir.join(PORT_FORWARDS_FILE))?;
+
+            let host_port = port_forwards
+                .lines()
+                .filter_map(|line| line.split_once(' '))
+                .find(|(guest, _)| *guest == guest_port)
+                .and_then(|(_, host)| host.parse::<u16>().ok())
+                .ok_or_else(|| {
+                    std::io::Error::other(format!("Port {guest_port} is not forwarded"))
+                })?;
+
+            let mut conn = TcpStream::connect(("127.0.0.1", host_port)).await?;
+            copy_bidirectional(&mut stdio, &mut conn).await?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,388 @@
+use std::ffi::OsStr;
+use std::fs::{create_dir_all, remove_dir_all, write};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use base64::Engine;
+use log::info;
+use tokio::process::Command;
+
+use super::daemon::Daemon;
+use super::machine::SWTPM_CMD;
+use super::triplet::Triplet;
+use crate::config::{ConfigFile, MachineProfile};
+
+const SWTPM_SETUP_CMD: &str = "/usr/bin/swtpm_setup";
+
+// The tpm2-pkcs11 module is installed in different locations by
+// different distributions.
+const PKCS11_MODULES: &[&str] = &[
+    "/usr/lib/x86_64-linux-gnu/pkcs11/libtpm2_pkcs11.so",
+    "/usr/lib/pkcs11/libtpm2_pkcs11.so",
+];
+
+const DEFAULT_PIN: &str = "0000";
+
+// The labels of the keys generated in the PKCS#11 token.
+const SSH_KEY_LABEL: &str = "ssh";
+const X509_KEY_LABEL: &str = "x509";
+
+/// What to provision in a new TPM in addition to its endorsement key
+#[derive(Default)]
+pub struct TpmInitOptions {
+    /// Create EK and platform certificates and export them
+    pub ek_cert: bool,
+    /// Generate an SSH key in the TPM and export the public key
+    pub ssh_key: bool,
+    /// Generate a key with a self-signed X.509 certificate in the TPM and export it
+    pub x509_cert: bool,
+    /// Replace an existing TPM state
+    pub force: bool,
+}
+
+/// The environment to run PKCS#11 tools against the TPM in
+struct Pkcs11 {
+    module: &'static str,
+    tcti: String,
+    store: PathBuf,
+    user_pin: String,
+}
+
+/// Run a command and fail if it does not exit successfully
+///
+/// Returns the standard output of the command.
+async fn run(cmd: &mut Command) -> std::io::Result<Vec<u8>> {
+    let program = cmd.as_std().get_program().to_string_lossy().into_owned();
+
+    let output = cmd.stderr(Stdio::inherit()).output().await?;
+
+    if !output.status.success() {
+        let msg = format!("{program} exited with {}", output.status);
+        return Err(std::io::Error::other(msg));
+    }
+
+    Ok(output.stdout)
+}
+
+/// Encode a DER element with the given tag
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let len = content.len();
+
+    let mut element = vec![tag];
+
+    match len {
+        0..=0x7f => element.push(len as u8),
+        0x80..=0xff => element.extend([0x81, len as u8]),
+        _ => element.extend([0x82, (len >> 8) as u8, len as u8]),
+    }
+
+    element.extend(content);
+    element
+}
+
+/// Encode a key URI in the PEM format understood by the OpenSSL pkcs11 provider
+fn pkcs11_uri_pem(uri: &str) -> String {
+    const VISIBLE_STRING: u8 = 0x1a;
+    const UTF8_STRING: u8 = 0x0c;
+    const SEQUENCE: u8 = 0x30;
+
+    let mut content = der(VISIBLE_STRING, b"PKCS#11 Provider URI v1.0");
+    content.extend(der(UTF8_STRING, uri.as_bytes()));
+
+    let encoded = base64::engine::general_purpose::STANDARD.encode(der(SEQUENCE, &content));
+
+    let mut pem = "-----BEGIN PKCS#11 PROVIDER URI-----\n".to_owned();
+
+    for line in encoded.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).unwrap());
+        pem.push('\n');
+    }
+
+    pem.push_str("-----END PKCS#11 PROVIDER URI-----\n");
+    pem
+}
+
+impl Pkcs11 {
+    fn command(&self, program: impl AsRef<OsStr>) -> Command {
+        let mut cmd = Command::new(program);
+
+        cmd.env("TPM2TOOLS_TCTI", &self.tcti)
+            .env("TPM2_PKCS11_TCTI", &self.tcti)
+            .env("TPM2_PKCS11_STORE", &self.store)
+            .env("PKCS11_PROVIDER_MODULE", self.module);
+
+        cmd
+    }
+
+    fn pkcs11_tool(&self) -> Command {
+        let mut cmd = self.command("pkcs11-tool");
+        cmd.arg("--module").arg(self.module);
+        cmd
+    }
+
+    async fn init_token(&self, so_pin: &str) -> std::io::Result<()> {
+        run(self
+            .pkcs11_tool()
+            .args(["--slot-index=0", "--init-token", "--label=tpm"])
+            .arg(format!("--so-pin={so_pin}")))
+        .await?;
+
+        run(self
+            .pkcs11_tool()
+            .args(["--slot-index=0", "--init-pin", "--login"])
+            .arg(format!("--so-pin={so_pin}"))
+            .arg(format!("--new-pin={}", self.user_pin)))
+        .await?;
+
+        Ok(())
+    }
+
+    async fn generate_key(&self, label: &str) -> std::io::Result<()> {
+        run(self
+            .pkcs11_tool()
+            .args(["--keypairgen", "--login", "--usage-sign"])
+            .args(["--key-type", "EC:prime256v1"])
+            .arg(format!("--pin={}", self.user_pin))
+            .arg(format!("--label={label}")))
+        .await?;
+
+        Ok(())
+    }
+
+    /// Generate an SSH key and return the public key
+    async fn ssh_key(&self) -> std::io::Result<Vec<u8>> {
+        self.generate_key(SSH_KEY_LABEL).await?;
+
+        run(self.command("ssh-keygen").arg("-D").arg(self.module)).await
+    }
+
+    /// Generate a key with a self-signed certificate and return the certificate
+    /// and a PEM file that refers to the key in the TPM
+    async fn x509_cert(
+        &self,
+        subject: &str,
+        work_dir: &Path,
+    ) -> std::io::Result<(Vec<u8>, String)> {
+        self.generate_key(X509_KEY_LABEL).await?;
+
+        let key_uri = format!("pkcs11:object={X509_KEY_LABEL};pin-value={}", self.user_pin);
+        let cert_path = work_dir.join("cert.pem");
+
+        run(self
+            .command("openssl")
+            .args(["req", "-new", "-x509", "-days", "36500"])
+            .args([
+                "-provider",
+                "default",
+                "-provider",
+                "base",
+                "-provider",
+                "pkcs11",
+            ])
+            .arg("-subj")
+            .arg(subject)
+            .arg("-key")
+            .arg(&key_uri)
+            .arg("-out")
+            .arg(&cert_path))
+        .await?;
+
+        // Store the certificate in the token alongside the key.
+        run(self
+            .pkcs11_tool()
+            .args(["--login", "--type", "cert"])
+            .arg(format!("--pin={}", self.user_pin))
+            .arg(format!("--label={X509_KEY_LABEL}"))
+            .arg("--write-object")
+            .arg(&cert_path))
+        .await?;
+
+        let cert = std::fs::read(&cert_path)?;
+
+        Ok((cert, pkcs11_uri_pem(&key_uri)))
+    }
+}
+
+/// Generate keys in the PKCS#11 token of a TPM state and export the public parts
+async fn provision_keys(
+    state: &Path,
+    export_dir: &Path,
+    work_dir: &Path,
+    triplet: &Triplet,
+    options: &TpmInitOptions,
+) -> std::io::Result<()> {
+    let server = work_dir.join("server.sock");
+
+    let mut swtpm = Command::new(SWTPM_CMD);
+
+    swtpm
+        .arg("socket")
+        .arg("--tpm2")
+        .arg("--tpmstate")
+        .arg(format!("backend-uri=file://{}", state.display()))
+        .arg("--server")
+        .arg(format!("type=unixio,path={}", server.display()))
+        .arg("--ctrl")
+        .arg(format!(
+            "type=unixio,path={}",
+            work_dir.join("ctrl.sock").display()
+        ))
+        .args(["--flags", "startup-clear", "--log", "fd=2"]);
+
+    let _swtpm = Daemon::spawn("swtpm", swtpm, server.clone(), &work_dir.join("swtpm.log")).await?;
+
+    let module = PKCS11_MODULES
+        .iter()
+        .find(|module| Path::new(module).exists())
+        .ok_or_else(|| std::io::Error::other("Could not find the tpm2-pkcs11 module"))?;
+
+    let so_pin = std::env::var("PKCS11_SO_PIN").unwrap_or_else(|_| DEFAULT_PIN.to_owned());
+    let user_pin = std::env::var("PKCS11_USER_PIN").unwrap_or_else(|_| DEFAULT_PIN.to_owned());
+
+    // The token metadata is required to use the keys later on,
+    // so it is kept in the machine dir.
+    let store = export_dir.join("tpm2_pkcs11");
+    create_dir_all(&store)?;
+
+    let pkcs11 = Pkcs11 {
+        module,
+        tcti: format!("swtpm:path={}", server.display()),
+        store,
+        user_pin,
+    };
+
+    pkcs11.init_token(&so_pin).await?;
+
+    if options.ssh_key {
+        let public_key = pkcs11.ssh_key().await?;
+        let path = export_dir.join("ssh.pub");
+
+        write(&path, public_key)?;
+
+        info!("Exported SSH public key to {}", path.display());
+    }
+
+    if options.x509_cert {
+        let subject = format!("/CN={}", triplet.machine_name());
+        let (cert, key_pem) = pkcs11.x509_cert(&subject, work_dir).await?;
+
+        let cert_path = export_dir.join("x509.cert.pem");
+        let key_path = export_dir.join("x509.key.pem");
+
+        write(&cert_path, cert)?;
+        write(&key_path, key_pem)?;
+
+        info!("Exported X.509 certificate to {}", cert_path.display());
+    }
+
+    Ok(())
+}
+
+/// Create a new TPM state for a machine type
+///
+/// The state is stored at the path that is copied into the run dir of each
+/// machine of this type.
+/// Public material, like EK certificates or SSH public keys, is exported to
+/// the `tpm` directory in the machine dir.
+pub async fn init(
+    cfg: &ConfigFile,
+    triplet: &Triplet,
+    options: &TpmInitOptions,
+) -> std::io::Result<()> {
+    let machine_config = cfg
+        .repositories
+        .get(triplet.owner())
+        .and_then(|repos| repos.get(triplet.repository()))
+        .and_then(|repo| repo.machines.get(triplet.machine_name()));
+
+    let machine_config = match machine_config {
+        Some(machine_config) => machine_config,
+        None => {
+            let msg = format!("Machine {triplet} is not configured");
+            return Err(std::io::Error::other(msg));
+        }
+    };
+
+    // qemu can only attach the TPM via the ISA bus, which microvm machines lack.
+    if machine_config.machine == MachineProfile::Microvm {
+        let msg = format!("The microvm machine {triplet} does not support a TPM");
+        return Err(std::io::Error::other(msg));
+    }
+
+    let base_dir = &cfg.host.base_dir;
+
+    let state = triplet.machine_tmp_state_path(base_dir);
+    let export_dir = triplet.machine_dir_path(base_dir).join("tpm");
+
+    if state.try_exists()? && !options.force {
+        let msg = format!(
+            "TPM state {} already exists. Use --force to replace it",
+            state.display()
+        );
+        return Err(std::io::Error::other(msg));
+    }
+
+    create_dir_all(&export_dir)?;
+
+    // Once a job persisted its disk image the state is a symlink to the TPM
+    // state of the current image generation.
+    // Replace the symlink instead of the persisted state.
+    if state.is_symlink() {
+        std::fs::remove_file(&state)?;
+    }
+
+    let mut setup = Command::new(SWTPM_SETUP_CMD);
+
+    setup
+        .args(["--tpm2", "--ecc", "--tpmstate"])
+        .arg(format!("file://{}", state.display()));
+
+    if options.force {
+        setup.arg("--overwrite");
+    }
+
+    if options.ek_cert {
+        setup
+            .args(["--create-ek-cert", "--create-platform-cert"])
+            .arg("--write-ek-cert-files")
+            .arg(&export_dir);
+    }
+
+    run(&mut setup).await?;
+
+    info!("Created TPM state {} for {triplet}", state.display());
+
+    if !options.ssh_key && !options.x509_cert {
+        return Ok(());
+    }
+
+    let work_dir = std::env::temp_dir().join(format!("forrest-tpm-{}", std::process::id()));
+    create_dir_all(&work_dir)?;
+
+    let res = provision_keys(&state, &export_dir, &work_dir, triplet, options).await;
+
+    let _ = remove_dir_all(&work_dir);
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::pkcs11_uri_pem;
+
+    #[test]
+    fn uri_pem() {
+        // Generated using `openssl asn1parse -genconf`, like the OpenSSL
+        // pkcs11 provider documentation suggests.
+        let expected = "-----BEGIN PKCS#11 PROVIDER URI-----
+MD4aGVBLQ1MjMTEgUHJvdmlkZXIgVVJJIHYxLjAMIXBrY3MxMTpvYmplY3Q9eDUw
+OTtwaW4tdmFsdWU9MDAwMA==
+-----END PKCS#11 PROVIDER URI-----
+";
+
+        assert_eq!(
+            pkcs11_uri_pem("pkcs11:object=x509;pin-value=0000"),
+            expected
+        );
+    }
+}
@@ -1,4 +1,5 @@
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 use log::debug;
 use serde::de::{Deserialize, Deserializer, Error};
@@ -119,7 +120,17 @@ impl Triplet {
             .join(format!("{}.img", self.machine_name))
     }
 
-    pub(super) fn machine_tmp_state_path(&self, base_dir_path: &Path) -> PathBuf {
+    /// A directory for additional files belonging to the machine type,
+    /// like public keys exported from its TPM
+    pub(super) fn machine_dir_path(&self, base_dir_path: &Path) -> PathBuf {
+        base_dir_path
+            .join("machines")
+            .join(&self.owner)
+            .join(&self.repository)
+            .join(&self.machine_name)
+    }
+
+    pub(crate) fn machine_tmp_state_path(&self, base_dir_path: &Path) -> PathBuf {
         base_dir_path
             .join("machines")
             .join(&self.owner)
@@ -151,13 +162,26 @@ impl<'de> Deserialize<'de> for Triplet {
     {
         let triplet_str: String = Deserialize::deserialize(deserializer)?;
 
+        triplet_str.parse().map_err(D::Error::custom)
+    }
+}
+
+impl FromStr for Triplet {
+    type Err = String;
+
+    fn from_str(triplet_str: &str) -> Result<Self, Self::Err> {
         let parts: Vec<&str> = triplet_str.split('/').collect();
-        let parts_len = parts.len();
 
-        if parts_len != 3 {
-            return Err(D::Error::invalid_length(
-                parts_len,
-                &"Expected string of format <user>/<repo>/<machine type>",
+        // The parts are used as path components, so make sure they can
+        // not be used to escape the base dir.
+        let parts_valid = parts.len() == 3
+            && parts
+                .iter()
+                .all(|part| !part.is_empty() && *part != "." && *part != "..");
+
+        if !parts_valid {
+            return Err(format!(
+                "Expected string of format <user>/<repo>/<machine type>, got '{triplet_str}'"
             ));
         }
 
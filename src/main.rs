@@ -1,61 +1,265 @@
+mod admin;
 mod api;
 mod artifacts;
 mod auth;
 mod config;
+mod ctl;
 mod ingres;
 mod jobs;
+mod logs;
 mod machines;
+mod metrics;
+mod status;
+
+const USAGE: &str = "Usage:
+    forrest [CONFIG]                  Run the Forrest service
+    forrest ssh-keys BASE_DIR         Print authorized_keys lines for the SSH jump host
+    forrest ssh-jump BASE_DIR LOGIN   Connect to a machine on behalf of a GitHub user
+    forrest tpm init CONFIG OWNER/REPO/MACHINE [--ek-cert] [--ssh-key] [--x509-cert] [--force]
+                                      Create the TPM state for a machine type
+    forrest image list CONFIG OWNER/REPO/MACHINE
+                                      List the persisted image generations of a machine type
+    forrest image show CONFIG OWNER/REPO/MACHINE [GENERATION]
+                                      Show where an image generation came from
+    forrest image rollback CONFIG OWNER/REPO/MACHINE [GENERATION]
+                                      Start new machines from an older image generation
+    forrest ctl status CONFIG [--json]
+                                      Show the RAM usage and supply of machines of the service
+    forrest ctl machines CONFIG [--json]
+                                      List the machines of the service
+    forrest ctl jobs CONFIG [--json]  List the jobs the service tracks
+    forrest ctl images CONFIG [--json]
+                                      List the image generations of all machine types
+    forrest ctl kill CONFIG RUNNER    Kill a machine, even if it runs a job
+    forrest ctl drain CONFIG [OWNER/REPO/MACHINE]
+                                      Stop starting new machines on the host or of a type
+    forrest ctl undrain CONFIG [OWNER/REPO/MACHINE]
+                                      Resume starting new machines
+    forrest ctl logs CONFIG [-f] RUNNER
+                                      Print (and follow) the serial console log of a machine";
 
 async fn forrest() -> anyhow::Result<()> {
-    let config_path = {
-        let mut args: Vec<String> = std::env::args().collect();
+    let args: Vec<String> = std::env::args().collect();
+    let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+
+    match args.as_slice() {
+        [_] => serve("config.yaml").await,
+        [_, "ssh-keys", base_dir] => ssh_keys(base_dir),
+        [_, "ssh-jump", base_dir, login] => ssh_jump(base_dir, login).await,
+        [_, "tpm", "init", config_path, triplet, flags @ ..] => {
+            tpm_init(config_path, triplet, flags).await
+        }
+        [_, "image", "list", config_path, triplet] => image_list(config_path, triplet),
+        [_, "image", "show", config_path, triplet] => image_show(config_path, triplet, None),
+        [_, "image", "show", config_path, triplet, generation] => {
+            image_show(config_path, triplet, Some(generation))
+        }
+        [_, "image", "rollback", config_path, triplet] => {
+            image_rollback(config_path, triplet, None)
+        }
+        [_, "image", "rollback", config_path, triplet, generation] => {
+            image_rollback(config_path, triplet, Some(generation))
+        }
+        [_, "ctl", "status", config_path, flags @ ..] => {
+            ctl::status(config_path, json_flag(flags)?).await
+        }
+        [_, "ctl", "machines", config_path, flags @ ..] => {
+            ctl::machines(config_path, json_flag(flags)?).await
+        }
+        [_, "ctl", "jobs", config_path, flags @ ..] => {
+            ctl::jobs(config_path, json_flag(flags)?).await
+        }
+        [_, "ctl", "images", config_path, flags @ ..] => {
+            ctl::images(config_path, json_flag(flags)?)
+        }
+        [_, "ctl", "kill", config_path, runner_name] => ctl::kill(config_path, runner_name).await,
+        [_, "ctl", "drain", config_path] => ctl::drain(config_path, None, true).await,
+        [_, "ctl", "drain", config_path, triplet] => {
+            ctl::drain(config_path, Some(triplet), true).await
+        }
+        [_, "ctl", "undrain", config_path] => ctl::drain(config_path, None, false).await,
+        [_, "ctl", "undrain", config_path, triplet] => {
+            ctl::drain(config_path, Some(triplet), false).await
+        }
+        [_, "ctl", "logs", config_path, "-f", runner_name] => {
+            ctl::logs(config_path, runner_name, true).await
+        }
+        [_, "ctl", "logs", config_path, runner_name] => {
+            ctl::logs(config_path, runner_name, false).await
+        }
+        [_, config_path] if !config_path.starts_with('-') => serve(config_path).await,
+        _ => anyhow::bail!("{USAGE}"),
+    }
+}
 
-        match args.len() {
-            1 => "config.yaml".to_owned(),
-            2 => args.remove(1),
-            _ => anyhow::bail!("Usage: {} [CONFIG]", args[0]),
+/// Check if the `--json` output of a `ctl` command was requested
+fn json_flag(flags: &[&str]) -> anyhow::Result<bool> {
+    match flags {
+        [] => Ok(false),
+        ["--json"] => Ok(true),
+        _ => anyhow::bail!("{USAGE}"),
+    }
+}
+
+/// Print the SSH keys of all users that may access a machine via the jump host
+///
+/// This is called by `sshd` via `AuthorizedKeysCommand`.
+/// It only gets the `host.base_dir`, as the config file contains secrets.
+fn ssh_keys(base_dir: &str) -> anyhow::Result<()> {
+    machines::print_authorized_keys(base_dir.as_ref())?;
+
+    Ok(())
+}
+
+/// Connect a user that logged into the SSH jump host to a machine
+///
+/// This is the forced command of the keys printed by `ssh_keys`.
+async fn ssh_jump(base_dir: &str, login: &str) -> anyhow::Result<()> {
+    machines::ssh_jump(base_dir.as_ref(), login).await?;
+
+    Ok(())
+}
+
+/// Create a TPM state that is used as template for all machines of a type
+async fn tpm_init(config_path: &str, triplet: &str, flags: &[&str]) -> anyhow::Result<()> {
+    let config = config::Config::new(config_path)?;
+
+    let triplet: machines::Triplet = triplet.parse().map_err(anyhow::Error::msg)?;
+
+    let mut options = machines::TpmInitOptions::default();
+
+    for flag in flags {
+        match *flag {
+            "--ek-cert" => options.ek_cert = true,
+            "--ssh-key" => options.ssh_key = true,
+            "--x509-cert" => options.x509_cert = true,
+            "--force" => options.force = true,
+            _ => anyhow::bail!("Unknown option {flag}\n{USAGE}"),
         }
-    };
+    }
+
+    machines::tpm_init(&config.get(), &triplet, &options).await?;
 
+    Ok(())
+}
+
+/// Print the persisted image generations of a machine type
+fn image_list(config_path: &str, triplet: &str) -> anyhow::Result<()> {
+    let config = config::Config::new(config_path)?;
+
+    let triplet: machines::Triplet = triplet.parse().map_err(anyhow::Error::msg)?;
+
+    machines::image_list(&config.get(), &triplet)?;
+
+    Ok(())
+}
+
+/// Print the provenance of an image generation (by default the current one)
+fn image_show(config_path: &str, triplet: &str, generation: Option<&str>) -> anyhow::Result<()> {
+    let config = config::Config::new(config_path)?;
+
+    let triplet: machines::Triplet = triplet.parse().map_err(anyhow::Error::msg)?;
+    let generation = generation.map(|g| g.parse()).transpose()?;
+
+    machines::image_show(&config.get(), &triplet, generation)?;
+
+    Ok(())
+}
+
+/// Make new machines of a type start from an older image generation
+///
+/// Defaults to the generation before the current one.
+fn image_rollback(
+    config_path: &str,
+    triplet: &str,
+    generation: Option<&str>,
+) -> anyhow::Result<()> {
+    let config = config::Config::new(config_path)?;
+
+    let triplet: machines::Triplet = triplet.parse().map_err(anyhow::Error::msg)?;
+    let generation = generation.map(|g| g.parse()).transpose()?;
+
+    let generation = machines::image_rollback(&config.get(), &triplet, generation)?;
+
+    println!("Rolled {triplet} back to generation {generation}");
+
+    Ok(())
+}
+
+async fn serve(config_path: &str) -> anyhow::Result<()> {
     // Read the config file.
     // The file will be re-read if it changed on disk at many points in the program,
     // allowing changes to be made while jobs are being executed.
-    let config = config::Config::new(&config_path)?;
+    let config = config::Config::new(config_path)?;
 
     // We use a private key to authenticate as a GitHub application
     // and derive installation tokens from it.
     // Use a central registry of cached installation tokens for efficiency.
     let auth = auth::Auth::new(&config)?;
 
+    // Count what happens over time, e.g. how long jobs wait for machines or
+    // how many GitHub API calls we make, for export to Prometheus.
+    let metrics = metrics::Metrics::new();
+
     // The machine manager handles our virtual machines and their relation with GitHub.
     // It makes sure we only spawn as many VMs as the host can fit,
     // that all machines we spawn eventually register as runners on GitHub,
     // stopping machines that are no longer required because
     // persisting disk images, cleaning up stale runners etc. etc.
-    let machine_manager = machines::Manager::new(config.clone(), auth.clone());
+    let machine_manager = machines::Manager::new(config.clone(), auth.clone(), metrics.clone());
 
     // The job manager keeps track of build jobs and their status and
     // communicates the demand for machines with the machine manager.
     // It gets its updates from from the webhook handler and poller below.
-    let job_manager = jobs::Manager::new(machine_manager.clone());
+    let job_manager = jobs::Manager::new(machine_manager.clone(), metrics.clone());
+
+    // Report what Forrest is doing right now, e.g. for dashboards.
+    let status = status::StatusHandler::new(machine_manager.clone(), job_manager.clone());
+
+    // Export the metrics collected above and the current number of machines
+    // in the Prometheus text format.
+    let metrics_handler = metrics::MetricsHandler::new(machine_manager.clone(), metrics.clone());
 
     // The main method to learn about new jobs to run is via webhooks.
     // These are POST requests sent by GitHub notifying us about events.
-    let webhook = ingres::WebhookHandler::new(config.clone(), auth.clone(), job_manager.clone());
+    let webhook = ingres::WebhookHandler::new(
+        config.clone(),
+        auth.clone(),
+        job_manager.clone(),
+        metrics.clone(),
+    );
 
     // Enable artifact upload from the guests. These are authenticated via a token given
     // to the machines and (optionally) an additional token stored e.g. as GitHub action secret.
     // They are limited in size by a quota set in the machine config.
     let artifacts = artifacts::ArtifactsHandler::new(machine_manager.clone());
 
-    // Provide a single unix domain socket for all API requests like webhook
-    // requests from GitHub or artifact uploads from  the guests.
-    let api = api::Api::new(config.clone(), artifacts, webhook)?;
+    // Allow developers to follow the serial console output of their machines,
+    // e.g. to find out why a runner never came online.
+    // These requests are authorized via a per-repository log token.
+    let logs = logs::LogsHandler::new(config.clone(), machine_manager.clone());
 
     // Our secondary source of information are periodic polls of the GitHub API.
     // These come in handy at startup or after network outages when we may have
     // missed webhooks.
-    let poller = ingres::Poller::new(config.clone(), auth.clone(), job_manager);
+    let poller = ingres::Poller::new(config.clone(), auth.clone(), job_manager, metrics);
+
+    // Let operators intervene without restarting the service, e.g. to kill
+    // stuck machines or force a poll.
+    // These requests are authorized via the admin token in the host config.
+    let admin = admin::AdminHandler::new(config.clone(), machine_manager.clone(), poller.trigger());
+
+    // Provide a single unix domain socket for all API requests like webhook
+    // requests from GitHub or artifact uploads from  the guests.
+    let api = api::Api::new(
+        config.clone(),
+        artifacts,
+        webhook,
+        logs,
+        status,
+        metrics_handler,
+        admin,
+    )?;
 
     log::info!("Startup complete. Handling requests");
 
@@ -0,0 +1,454 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hyper::body::Incoming;
+use hyper::{Method, Request, Response, StatusCode};
+
+use crate::machines::{Manager as MachineManager, ManagerStatus, Triplet};
+
+/// The upper bounds (in seconds) of the buckets of duration histograms
+///
+/// Jobs may wait for a machine from a few seconds up to hours if the host is
+/// busy, while machines usually take a few minutes to boot.
+const DURATION_BUCKETS: &[f64] = &[
+    5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0, 3600.0, 7200.0,
+];
+
+/// The label names and values of a single time series
+type Labels = Vec<(&'static str, String)>;
+
+#[derive(Default)]
+struct Histogram {
+    /// The number of observations per bucket, cumulative like in the text format
+    buckets: [u64; DURATION_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    queue_wait: BTreeMap<Labels, Histogram>,
+    boot_duration: BTreeMap<Labels, Histogram>,
+    start_timeouts: BTreeMap<Labels, u64>,
+    persists: BTreeMap<Labels, u64>,
+    artifact_bytes: BTreeMap<Labels, u64>,
+    artifact_quota_rejections: BTreeMap<Labels, u64>,
+    webhook_deliveries: BTreeMap<Labels, u64>,
+    github_api_calls: u64,
+    github_api_errors: u64,
+    github_rate_limit_remaining: BTreeMap<Labels, u64>,
+}
+
+/// Counters and histograms of things that happened since Forrest started
+///
+/// These are collected all over the program and exported in the
+/// Prometheus text format via `GET /metrics`.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    inner: Arc<Mutex<Inner>>,
+}
+
+pub struct MetricsHandler {
+    machine_manager: MachineManager,
+    metrics: Metrics,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+
+        for (bucket, le) in self.buckets.iter_mut().zip(DURATION_BUCKETS) {
+            if secs <= *le {
+                *bucket += 1;
+            }
+        }
+
+        self.sum += secs;
+        self.count += 1;
+    }
+}
+
+fn machine_labels(triplet: &Triplet) -> Labels {
+    vec![("machine", triplet.to_string())]
+}
+
+/// Write a label set like `{machine="hnez/forrest/build",status="running"}`
+fn write_labels(out: &mut String, labels: &[(&str, String)]) {
+    if labels.is_empty() {
+        return;
+    }
+
+    out.push('{');
+
+    for (i, (name, value)) in labels.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+
+        let value = value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n");
+
+        write!(out, "{name}=\"{value}\"").unwrap();
+    }
+
+    out.push('}');
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {name} {help}").unwrap();
+    writeln!(out, "# TYPE {name} {kind}").unwrap();
+}
+
+fn write_sample(out: &mut String, name: &str, labels: &[(&str, String)], value: impl Display) {
+    out.push_str(name);
+    write_labels(out, labels);
+    writeln!(out, " {value}").unwrap();
+}
+
+fn write_family(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    samples: &BTreeMap<Labels, u64>,
+) {
+    write_header(out, name, kind, help);
+
+    for (labels, value) in samples {
+        write_sample(out, name, labels, value);
+    }
+}
+
+fn write_histograms(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    histograms: &BTreeMap<Labels, Histogram>,
+) {
+    write_header(out, name, "histogram", help);
+
+    let bucket_name = format!("{name}_bucket");
+
+    for (labels, histogram) in histograms {
+        let mut bucket_labels = labels.clone();
+        bucket_labels.push(("le", String::new()));
+
+        for (le, count) in DURATION_BUCKETS.iter().zip(histogram.buckets) {
+            bucket_labels.last_mut().unwrap().1 = le.to_string();
+            write_sample(out, &bucket_name, &bucket_labels, count);
+        }
+
+        bucket_labels.last_mut().unwrap().1 = "+Inf".into();
+        write_sample(out, &bucket_name, &bucket_labels, histogram.count);
+
+        write_sample(out, &format!("{name}_sum"), labels, histogram.sum);
+        write_sample(out, &format!("{name}_count"), labels, histogram.count);
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn inner(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap()
+    }
+
+    /// A job of type `triplet` waited `duration` from being queued until a machine picked it up
+    pub fn queue_wait(&self, triplet: &Triplet, duration: Duration) {
+        self.inner()
+            .queue_wait
+            .entry(machine_labels(triplet))
+            .or_default()
+            .observe(duration);
+    }
+
+    /// A machine of type `triplet` took `duration` to go from starting to waiting for a job
+    pub fn boot_duration(&self, triplet: &Triplet, duration: Duration) {
+        self.inner()
+            .boot_duration
+            .entry(machine_labels(triplet))
+            .or_default()
+            .observe(duration);
+    }
+
+    /// A machine of type `triplet` failed to come up in time and was killed
+    pub fn start_timeout(&self, triplet: &Triplet) {
+        *self
+            .inner()
+            .start_timeouts
+            .entry(machine_labels(triplet))
+            .or_default() += 1;
+    }
+
+    /// The disk image of a machine of type `triplet` was (or failed to be) persisted
+    ///
+    /// The `result` is one of `success`, `failure` or `rejected`.
+    /// Persists are `rejected` if the job left a persist file that does not
+    /// match the persistence token, which is not a failure of the host.
+    pub fn persist(&self, triplet: &Triplet, result: &'static str) {
+        let mut labels = machine_labels(triplet);
+        labels.push(("result", result.into()));
+
+        *self.inner().persists.entry(labels).or_default() += 1;
+    }
+
+    /// A machine of type `triplet` uploaded `bytes` to the artifact store `artifact`
+    pub fn artifact_bytes(&self, triplet: &Triplet, artifact: &str, bytes: u64) {
+        let mut labels = machine_labels(triplet);
+        labels.push(("artifact", artifact.into()));
+
+        *self.inner().artifact_bytes.entry(labels).or_default() += bytes;
+    }
+
+    /// An artifact upload by a machine of type `triplet` was rejected because it exceeded the quota
+    pub fn artifact_quota_rejection(&self, triplet: &Triplet, artifact: &str) {
+        let mut labels = machine_labels(triplet);
+        labels.push(("artifact", artifact.into()));
+
+        *self
+            .inner()
+            .artifact_quota_rejections
+            .entry(labels)
+            .or_default() += 1;
+    }
+
+    /// A webhook of type `event` was delivered and its signature check had the result `verification`
+    ///
+    /// The `verification` is one of `valid`, `invalid` or `missing`.
+    /// Callers should only pass the event type from the request for valid deliveries.
+    pub fn webhook_delivery(&self, event: &str, verification: &'static str) {
+        let labels = vec![
+            ("event", event.into()),
+            ("verification", verification.into()),
+        ];
+
+        *self.inner().webhook_deliveries.entry(labels).or_default() += 1;
+    }
+
+    /// Count a GitHub API call and whether it failed
+    pub fn github_api_call<T>(&self, res: &octocrab::Result<T>) {
+        let mut inner = self.inner();
+
+        inner.github_api_calls += 1;
+
+        if res.is_err() {
+            inner.github_api_errors += 1;
+        }
+    }
+
+    /// The installation of `owner` has `remaining` GitHub API calls left until the limit resets
+    pub fn github_rate_limit_remaining(&self, owner: &str, remaining: u64) {
+        self.inner()
+            .github_rate_limit_remaining
+            .insert(vec![("owner", owner.into())], remaining);
+    }
+
+    /// Render the metrics in the Prometheus text format
+    ///
+    /// The number of machines per status is not collected over time but
+    /// taken from the `machines` snapshot.
+    fn render(&self, machines: &ManagerStatus) -> String {
+        let mut machine_counts: BTreeMap<Labels, u64> = BTreeMap::new();
+
+        for machine in &machines.machines {
+            let labels = vec![
+                ("machine", machine.triplet.clone()),
+                ("status", machine.status.clone()),
+            ];
+
+            *machine_counts.entry(labels).or_default() += 1;
+        }
+
+        let inner = self.inner();
+        let mut out = String::new();
+
+        write_family(
+            &mut out,
+            "forrest_machines",
+            "gauge",
+            "The number of machines per machine type and status",
+            &machine_counts,
+        );
+
+        write_histograms(
+            &mut out,
+            "forrest_queue_wait_seconds",
+            "The time from a job being queued until a machine picked it up",
+            &inner.queue_wait,
+        );
+
+        write_histograms(
+            &mut out,
+            "forrest_boot_duration_seconds",
+            "The time from a machine starting until it waits for a job",
+            &inner.boot_duration,
+        );
+
+        write_family(
+            &mut out,
+            "forrest_start_timeouts_total",
+            "counter",
+            "The number of machines that failed to come up in time",
+            &inner.start_timeouts,
+        );
+
+        write_family(
+            &mut out,
+            "forrest_persists_total",
+            "counter",
+            "The number of disk images that were or failed to be persisted",
+            &inner.persists,
+        );
+
+        write_family(
+            &mut out,
+            "forrest_artifact_bytes_total",
+            "counter",
+            "The number of bytes uploaded to artifact stores",
+            &inner.artifact_bytes,
+        );
+
+        write_family(
+            &mut out,
+            "forrest_artifact_quota_rejections_total",
+            "counter",
+            "The number of artifact uploads rejected for exceeding the quota",
+            &inner.artifact_quota_rejections,
+        );
+
+        write_family(
+            &mut out,
+            "forrest_webhook_deliveries_total",
+            "counter",
+            "The number of webhook deliveries by event type and signature verification result",
+            &inner.webhook_deliveries,
+        );
+
+        write_header(
+            &mut out,
+            "forrest_github_api_calls_total",
+            "counter",
+            "The number of GitHub API calls made",
+        );
+        write_sample(
+            &mut out,
+            "forrest_github_api_calls_total",
+            &[],
+            inner.github_api_calls,
+        );
+
+        write_header(
+            &mut out,
+            "forrest_github_api_errors_total",
+            "counter",
+            "The number of GitHub API calls that failed",
+        );
+        write_sample(
+            &mut out,
+            "forrest_github_api_errors_total",
+            &[],
+            inner.github_api_errors,
+        );
+
+        write_family(
+            &mut out,
+            "forrest_github_rate_limit_remaining",
+            "gauge",
+            "The number of GitHub API calls left per installation until the rate limit resets",
+            &inner.github_rate_limit_remaining,
+        );
+
+        out
+    }
+}
+
+impl MetricsHandler {
+    pub fn new(machine_manager: MachineManager, metrics: Metrics) -> Self {
+        Self {
+            machine_manager,
+            metrics,
+        }
+    }
+
+    /// Handle a `GET /metrics` request
+    ///
+    /// Returns the metrics in the Prometheus text format.
+    pub async fn handle(&self, request: Request<Incoming>) -> anyhow::Result<Response<String>> {
+        if request.method() != Method::GET {
+            return Ok(Response::builder()
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .body("Only GET is supported\n".into())
+                .unwrap());
+        }
+
+        if request.uri().path() != "/metrics" {
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body("File not found\n".into())
+                .unwrap());
+        }
+
+        let body = self.metrics.render(&self.machine_manager.status());
+
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(body)
+            .unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Metrics;
+    use crate::machines::{ManagerStatus, Triplet};
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::new();
+        let triplet = Triplet::new("owner", "repo", "machine");
+
+        metrics.boot_duration(&triplet, Duration::from_secs(20));
+        metrics.boot_duration(&triplet, Duration::from_secs(100));
+        metrics.webhook_delivery("workflow_job", "valid");
+        metrics.webhook_delivery("say \"hi\"", "valid");
+
+        let machines = ManagerStatus {
+            machines: Vec::new(),
+            supply: Vec::new(),
+            ram_total: 0,
+            ram_used: 0,
+            ram_available: 0,
+            host_drained: false,
+            drained_machines: Vec::new(),
+        };
+
+        let text = metrics.render(&machines);
+        let lines: Vec<&str> = text.lines().collect();
+
+        let expected = [
+            "# TYPE forrest_boot_duration_seconds histogram",
+            "forrest_boot_duration_seconds_bucket{machine=\"owner/repo/machine\",le=\"15\"} 0",
+            "forrest_boot_duration_seconds_bucket{machine=\"owner/repo/machine\",le=\"30\"} 1",
+            "forrest_boot_duration_seconds_bucket{machine=\"owner/repo/machine\",le=\"120\"} 2",
+            "forrest_boot_duration_seconds_bucket{machine=\"owner/repo/machine\",le=\"+Inf\"} 2",
+            "forrest_boot_duration_seconds_sum{machine=\"owner/repo/machine\"} 120",
+            "forrest_boot_duration_seconds_count{machine=\"owner/repo/machine\"} 2",
+            "forrest_webhook_deliveries_total{event=\"say \\\"hi\\\"\",verification=\"valid\"} 1",
+            "forrest_webhook_deliveries_total{event=\"workflow_job\",verification=\"valid\"} 1",
+            "forrest_github_api_calls_total 0",
+        ];
+
+        for line in expected {
+            assert!(lines.contains(&line), "{line} is missing in:\n{text}");
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use hyper::body::Incoming;
+use hyper::{Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::jobs::{JobInfo, Manager as JobManager};
+use crate::machines::{Manager as MachineManager, ManagerStatus};
+
+pub struct StatusHandler {
+    machine_manager: MachineManager,
+    job_manager: JobManager,
+}
+
+/// The response to a `GET /status` request
+#[derive(Serialize, Deserialize)]
+pub struct Status {
+    #[serde(flatten)]
+    pub machines: ManagerStatus,
+    pub jobs: Vec<JobInfo>,
+}
+
+impl StatusHandler {
+    pub fn new(machine_manager: MachineManager, job_manager: JobManager) -> Self {
+        Self {
+            machine_manager,
+            job_manager,
+        }
+    }
+
+    /// Handle a `GET /status` request
+    ///
+    /// Returns the machines, the jobs we track and the resources they use
+    /// as JSON, e.g. for dashboards.
+    pub async fn handle(&self, request: Request<Incoming>) -> anyhow::Result<Response<String>> {
+        if request.method() != Method::GET {
+            return Ok(Response::builder()
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .body("Only GET is supported\n".into())
+                .unwrap());
+        }
+
+        if request.uri().path() != "/status" {
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body("File not found\n".into())
+                .unwrap());
+        }
+
+        let status = Status {
+            machines: self.machine_manager.status(),
+            jobs: self.job_manager.status(),
+        };
+
+        let body = serde_json::to_string_pretty(&status)?;
+
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(body)
+            .unwrap())
+    }
+}